@@ -12,9 +12,64 @@ function LogLine(props) {
   );
 }
 
+const API = "http://localhost:8000/api";
+
+// Requests carry the session cookie set when logging in.
+function api(path, options = {}) {
+  return fetch(`${API}${path}`, { credentials: "include", ...options });
+}
+
+// Without group_by, /api/metrics returns a single series, or none without data.
+function points(series) {
+  return series.length > 0 ? series[0].points : [];
+}
+
+class Login extends React.Component {
+
+  state = {
+    email: "",
+    password: "",
+    error: "",
+  }
+
+  login = (event) => {
+    event.preventDefault();
+
+    api("/sessions", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ email: this.state.email, password: this.state.password }),
+    })
+    .then((response) => {
+      if (!response.ok) {
+        throw new Error("Wrong email or password");
+      }
+      return response.json();
+    })
+    .then((data) => this.props.onLogin(data.user))
+    .catch((error) => this.setState({ error: error.message }));
+  }
+
+  render() {
+    return (
+      <form onSubmit={this.login}>
+        <h2>Log in</h2>
+        <label>Email</label><br />
+        <input type="email" value={this.state.email} onChange={(event) => this.setState({ email: event.target.value })} /> <br />
+        <label>Password</label><br />
+        <input type="password" value={this.state.password} onChange={(event) => this.setState({ password: event.target.value })} /> <br />
+        <button type="submit">Log in</button>
+        <p>{this.state.error}</p>
+      </form>
+    );
+  }
+}
+
 class App extends React.Component {
 
   state = {
+    // Unknown until /api/me answers, then the logged in user or false.
+    user: null,
     cpuUtilization: [],
     memoryUsed: [],
     memoryAvailable: [],
@@ -25,14 +80,31 @@ class App extends React.Component {
     searchDate: "",
   }
 
+  // The session expired or was revoked.
+  checkLoggedIn = (response) => {
+    if (response.status === 401) {
+      this.logout();
+      throw new Error("Not logged in");
+    }
+    return response;
+  }
+
   fetchData = () => {
-    fetch("http://localhost:8000/api/metrics?name=system.cpu.utilization")
+    if (!this.state.user) {
+      return;
+    }
+
+    api("/metrics?name=system.cpu.utilization")
+    .then(this.checkLoggedIn)
     .then((response) => response.json())
-    .then((data) => this.setState({cpuUtilization: data}));
+    .then((series) => this.setState({cpuUtilization: points(series)}))
+    .catch(() => {});
 
-    fetch("http://localhost:8000/api/metrics?name=system.mem.used")
+    api("/metrics?name=system.mem.used")
+    .then(this.checkLoggedIn)
     .then((response) => response.json())
-    .then((data) => this.setState({ memoryUsed: data}));
+    .then((series) => this.setState({ memoryUsed: points(series)}))
+    .catch(() => {});
 
     let offset = this.state.logsOffset ? `offset=${this.state.logsOffset}` : "";
 
@@ -40,7 +112,8 @@ class App extends React.Component {
       return;
     }
 
-    fetch(`http://localhost:8000/api/logs?${offset}`)
+    api(`/logs?${offset}`)
+    .then(this.checkLoggedIn)
     .then((response) => response.json())
     .then((data) => {
       if (data.length > 0) {
@@ -49,15 +122,25 @@ class App extends React.Component {
         let logs = data.concat(last_logs);
         this.setState({ logs: logs, logsOffset: offset, searchDate: "" });
       }
-    });
+    })
+    .catch(() => {});
   }
 
   searchLogs = (event) => {
-    fetch(`http://localhost:8000/api/logs/search?term=${this.state.searchTerm}&created_at=${this.state.searchDate}`)
+    api(`/logs/search?term=${this.state.searchTerm}&created_at=${this.state.searchDate}`)
+    .then(this.checkLoggedIn)
     .then((response) => response.json())
     .then((data) => {
       this.setState({ logs: data, logsOffset: null });
     })
+    .catch(() => {});
+  }
+
+  logout = () => {
+    if (this.state.user) {
+      api("/sessions", { method: "DELETE" });
+    }
+    this.setState({ user: false, logs: [], logsOffset: null });
   }
 
   updateInput = (name) => {
@@ -69,6 +152,11 @@ class App extends React.Component {
   }
 
   componentDidMount() {
+    api("/me")
+    .then((response) => response.ok ? response.json() : false)
+    .then((user) => this.setState({ user: user }))
+    .catch(() => this.setState({ user: false }));
+
     const interval = setInterval(this.fetchData, 1000);
     this.setState({interval: interval});
   }
@@ -78,10 +166,25 @@ class App extends React.Component {
   }
 
   render() {
+    if (this.state.user === null) {
+      return null;
+    }
+
+    if (!this.state.user) {
+      return (
+        <div className="App" style={{
+          padding: 25
+        }}>
+          <Login onLogin={(user) => this.setState({ user: user })} />
+        </div>
+      );
+    }
+
     return (
       <div className="App" style={{
         padding: 25
       }}>
+        <p>{this.state.user.email} ({this.state.user.role}) <button onClick={this.logout}>Log out</button></p>
         <h2>Metrics</h2>
         <h3>
           <pre style={{ width: 100 }}>system.cpu.utilization</pre>
@@ -0,0 +1,4 @@
+-- Structured fields parsed from log lines by the agent, e.g. from JSON logs.
+ALTER TABLE public.logs ADD COLUMN fields JSONB;
+
+CREATE INDEX ON public.logs USING gin(fields jsonb_path_ops);
@@ -0,0 +1,11 @@
+-- Keys agents use to send metrics and logs. Only a hash of each key is kept.
+CREATE TABLE public.api_keys (
+	id BIGSERIAL PRIMARY KEY,
+	name VARCHAR NOT NULL,
+	prefix VARCHAR NOT NULL, -- start of the key, to tell keys apart
+	key_hash VARCHAR NOT NULL UNIQUE, -- hex SHA-256 of the key
+	scopes VARCHAR[] NOT NULL,
+	created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+	last_used_at TIMESTAMP WITHOUT TIME ZONE,
+	revoked_at TIMESTAMP WITHOUT TIME ZONE
+);
@@ -0,0 +1,19 @@
+-- People using the frontend and the query API.
+CREATE TABLE public.users (
+	id BIGSERIAL PRIMARY KEY,
+	email VARCHAR NOT NULL UNIQUE,
+	password_hash VARCHAR NOT NULL, -- Argon2id, PHC string format
+	role VARCHAR NOT NULL, -- viewer, editor or admin
+	created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+
+-- Logged in users. Only a hash of each session token is kept.
+CREATE TABLE public.sessions (
+	id BIGSERIAL PRIMARY KEY,
+	user_id bigint NOT NULL REFERENCES public.users(id) ON DELETE CASCADE,
+	token_hash VARCHAR NOT NULL UNIQUE, -- hex SHA-256 of the token
+	created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+	expires_at TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+
+CREATE INDEX ON public.sessions USING btree(user_id);
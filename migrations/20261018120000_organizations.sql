@@ -0,0 +1,37 @@
+-- Tenants: teams sharing one server, each only seeing its own data.
+CREATE TABLE public.organizations (
+	id BIGSERIAL PRIMARY KEY,
+	name VARCHAR NOT NULL UNIQUE,
+	created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+
+-- Everything written so far belongs to the first organization.
+INSERT INTO public.organizations (id, name, created_at) VALUES (1, 'default', TIMEZONE('UTC', NOW()));
+SELECT setval('public.organizations_id_seq', 1);
+
+ALTER TABLE public.metrics ADD COLUMN organization_id bigint NOT NULL DEFAULT 1 REFERENCES public.organizations(id);
+ALTER TABLE public.metric_tags ADD COLUMN organization_id bigint NOT NULL DEFAULT 1 REFERENCES public.organizations(id);
+ALTER TABLE public.logs ADD COLUMN organization_id bigint NOT NULL DEFAULT 1 REFERENCES public.organizations(id);
+ALTER TABLE public.api_keys ADD COLUMN organization_id bigint NOT NULL DEFAULT 1 REFERENCES public.organizations(id);
+ALTER TABLE public.users ADD COLUMN organization_id bigint NOT NULL DEFAULT 1 REFERENCES public.organizations(id);
+
+-- From now on, the server always says which organization rows belong to.
+ALTER TABLE public.metrics ALTER COLUMN organization_id DROP DEFAULT;
+ALTER TABLE public.metric_tags ALTER COLUMN organization_id DROP DEFAULT;
+ALTER TABLE public.logs ALTER COLUMN organization_id DROP DEFAULT;
+ALTER TABLE public.api_keys ALTER COLUMN organization_id DROP DEFAULT;
+ALTER TABLE public.users ALTER COLUMN organization_id DROP DEFAULT;
+
+CREATE INDEX ON public.metrics USING btree(organization_id, metric_name_id, recorded_at);
+CREATE INDEX ON public.logs USING btree(organization_id, id);
+CREATE INDEX ON public.logs USING gin(organization_id, created_at, log_parts);
+
+-- What each organization sent, per day.
+CREATE TABLE public.organization_usage (
+	organization_id bigint NOT NULL REFERENCES public.organizations(id),
+	day DATE NOT NULL,
+	points BIGINT NOT NULL DEFAULT 0,
+	log_lines BIGINT NOT NULL DEFAULT 0,
+	log_bytes BIGINT NOT NULL DEFAULT 0,
+	PRIMARY KEY (organization_id, day)
+);
@@ -0,0 +1,2 @@
+-- Tag filters and groups look up the tags of each point.
+CREATE INDEX ON public.metric_tags USING btree(metric_id, tag_name_id);
@@ -1,8 +1,32 @@
 // Agent collecting metrics and logs.
 
-use async_std::prelude::*;
 use std::collections::HashMap;
-use std::ops::{Deref, DerefMut};
+
+pub mod checkpoint;
+pub mod config;
+pub mod dead_letter;
+pub mod discovery;
+pub mod filter;
+pub mod grok;
+pub mod multiline;
+pub mod parse;
+pub mod queue;
+pub mod ratelimit;
+pub mod redact;
+pub mod retry;
+pub mod self_metrics;
+pub mod spool;
+pub mod statsd;
+pub mod tailer;
+pub mod upload;
+pub mod watcher;
+
+use checkpoint::Checkpoints;
+use config::Config;
+use queue::Queue;
+use redact::Redactor;
+use self_metrics::SelfMetrics;
+use upload::Uploader;
 
 // System metrics
 use sysinfo::{ProcessorExt, SystemExt};
@@ -14,7 +38,7 @@ pub struct Metric {
     pub tags: HashMap<String, String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LogLevel {
     Debug,
     Notice,
@@ -24,12 +48,84 @@ pub enum LogLevel {
     Fatal,
 }
 
+impl LogLevel {
+    /// Parse a level name as written in the configuration, e.g. "warning".
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name.to_lowercase().as_ref() {
+            "debug" => Some(LogLevel::Debug),
+            "notice" => Some(LogLevel::Notice),
+            "info" => Some(LogLevel::Info),
+            "warning" => Some(LogLevel::Warning),
+            "error" => Some(LogLevel::Error),
+            "fatal" => Some(LogLevel::Fatal),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Notice => "notice",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+            LogLevel::Fatal => "fatal",
+        }
+    }
+
+    /// How the level is stored in the database.
+    pub fn to_i16(&self) -> i16 {
+        match self {
+            LogLevel::Debug => 0,
+            LogLevel::Notice => 1,
+            LogLevel::Info => 2,
+            LogLevel::Warning => 3,
+            LogLevel::Error => 4,
+            LogLevel::Fatal => 5,
+        }
+    }
+
+    pub fn from_i16(level: i16) -> Option<LogLevel> {
+        match level {
+            0 => Some(LogLevel::Debug),
+            1 => Some(LogLevel::Notice),
+            2 => Some(LogLevel::Info),
+            3 => Some(LogLevel::Warning),
+            4 => Some(LogLevel::Error),
+            5 => Some(LogLevel::Fatal),
+            _ => None,
+        }
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct LogLine {
     pub line: String,
     pub level: Option<LogLevel>,
     pub created_at: Option<String>,
     pub tags: HashMap<String, String>,
+    /// Structured fields parsed from the line, e.g. from JSON logs.
+    #[serde(default)]
+    pub fields: HashMap<String, String>,
+}
+
+impl queue::Item for Metric {
+    fn size(&self) -> usize {
+        self.name.len() + tags_size(&self.tags) + 32
+    }
+}
+
+impl queue::Item for LogLine {
+    fn size(&self) -> usize {
+        self.line.len() + tags_size(&self.tags) + tags_size(&self.fields) + 64
+    }
+}
+
+/// Keys and values, plus the JSON around them.
+fn tags_size(tags: &HashMap<String, String>) -> usize {
+    tags.iter()
+        .map(|(key, value)| key.len() + value.len() + 6)
+        .sum()
 }
 
 impl LogLine {
@@ -45,371 +141,488 @@ impl LogLine {
     }
 }
 
-pub async fn launch() {
-    // Custom metrics collector
-    let custom_metrics = tokio::task::spawn(async move {
-        let sock = async_std::net::UdpSocket::bind("0.0.0.0:1337")
-            .await
-            .unwrap();
-
-        println!("Listening for custom metrics on UDP port 1337");
+pub async fn launch(config: Config) {
+    let config = std::sync::Arc::new(config);
+    let hostname = gethostname::gethostname()
+        .into_string()
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    // Tags attached to everything this agent sends.
+    let mut global_tags = config.tags.clone();
+    global_tags.insert("hostname".to_string(), hostname);
+
+    let self_metrics = std::sync::Arc::new(SelfMetrics::new());
+
+    let spool = match &config.spool {
+        Some(spool) => match spool::Spool::open(
+            std::path::Path::new(&spool.dir),
+            spool.max_bytes,
+            self_metrics.clone(),
+        ) {
+            Ok(spool) => Some(spool),
+            Err(err) => {
+                println!("Could not open spool: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-        loop {
-            let mut buf = vec![0u8; 512];
-            let (n, peer) = sock.recv_from(&mut buf).await.unwrap();
+    let checkpoints = match Checkpoints::load(config.state_file.as_deref()) {
+        Ok(checkpoints) => std::sync::Arc::new(checkpoints),
+        Err(err) => {
+            println!("Could not load log positions: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-            println!("Received {} bytes from peer {}", n, peer);
+    let dead_letter =
+        match dead_letter::DeadLetter::new(config.dead_letter_dir.as_deref(), self_metrics.clone())
+        {
+            Ok(dead_letter) => dead_letter,
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        };
 
-            tokio::task::spawn(async move {
-                process_metric(buf).await;
-            });
+    let uploader = match Uploader::new(
+        &config.server_url,
+        // Validated with the rest of the config.
+        config.upload.compression().unwrap(),
+        config.retry.policy(),
+        config
+            .api_key
+            .clone()
+            .or_else(|| std::env::var("METRICSCAT_API_KEY").ok())
+            .as_deref(),
+        config.tls.as_ref(),
+        spool,
+        dead_letter,
+    ) {
+        Ok(uploader) => std::sync::Arc::new(uploader),
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
         }
-    });
+    };
+    tokio::task::spawn(upload::replay_forever(uploader.clone()));
+
+    let mut tasks = Vec::new();
+
+    // Validated with the rest of the config.
+    let policy = config.queue.policy().unwrap();
+    let metrics: std::sync::Arc<Queue<Metric>> = std::sync::Arc::new(Queue::new(
+        "metrics",
+        config.queue.capacity,
+        policy,
+        self_metrics.clone(),
+    ));
+    let log_lines: std::sync::Arc<Queue<LogLine>> = std::sync::Arc::new(Queue::new(
+        "logs",
+        config.queue.capacity,
+        policy,
+        self_metrics.clone(),
+    ));
+
+    // Senders, one request at a time so a slow server fills the queues
+    // instead of piling up requests.
+    let max_items = config.upload.max_batch_items;
+    let max_bytes = config.upload.max_batch_bytes;
+    let max_latency = tokio::time::Duration::from_millis(config.upload.max_batch_latency_ms);
+
+    // Stops the senders on shutdown, once they shipped what's queued.
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let mut senders = Vec::new();
+
+    {
+        let metrics = metrics.clone();
+        let uploader = uploader.clone();
+        let mut stopped = stopped.clone();
+
+        senders.push(tokio::task::spawn(async move {
+            loop {
+                // A batch being sent is finished before stopping.
+                let batch = tokio::select! {
+                    batch = metrics.pop_batch(max_items, max_bytes, max_latency) => batch,
+                    _ = stopped.changed() => break,
+                };
 
-    // System metrics (system.*)
-    let system_metrics = tokio::task::spawn(async move {
-        let mut system = sysinfo::System::new_all();
-        let duration = tokio::time::Duration::from_millis(1_000);
-        let hostname = gethostname::gethostname();
-        let tags = HashMap::from([(
-            "hostname".to_string(),
-            hostname.into_string().unwrap_or("unknown".to_string()),
-        )]);
+                if !batch.is_empty() {
+                    uploader.send_metrics(&batch).await;
+                }
+            }
 
-        println!("Starting collection of system metrics");
+            loop {
+                let batch = metrics.pop(max_items, max_bytes);
 
-        loop {
-            tokio::time::sleep(duration).await;
-            system.refresh_all();
-
-            // Memory
-            let metrics = vec![
-                Metric {
-                    name: "system.mem.total".to_string(),
-                    value: system.total_memory() as f64,
-                    tags: tags.clone(),
-                },
-                Metric {
-                    name: "system.mem.used".to_string(),
-                    value: system.used_memory() as f64,
-                    tags: tags.clone(),
-                },
-                Metric {
-                    name: "system.cpu.utilization".to_string(),
-                    value: system
-                        .processors()
-                        .iter()
-                        .map(|cpu| cpu.cpu_usage() as f64)
-                        .sum::<f64>()
-                        / system.processors().len() as f64,
-                    tags: tags.clone(),
-                },
-            ];
+                if batch.is_empty() {
+                    break;
+                }
 
-            tokio::task::spawn(async move {
-                let _ = send_metrics(metrics).await;
-            });
-        }
-    });
+                uploader.send_metrics(&batch).await;
+            }
+        }));
+    }
 
-    // Logs collector.
-    // Add your log files here!
-    let log_files = vec![
-        "/var/log/postgresql/postgresql-12-main.log",
-        "/some/random/file.log",
-        // "/var/log/dpkg.log",
-    ];
+    {
+        let log_lines = log_lines.clone();
+        let uploader = uploader.clone();
+        let mut stopped = stopped.clone();
 
-    let log_lines = std::sync::Arc::new(tokio::sync::Mutex::new(Vec::new()));
-    let s_log_lines = std::sync::Arc::clone(&log_lines);
+        senders.push(tokio::task::spawn(async move {
+            loop {
+                let batch = tokio::select! {
+                    batch = log_lines.pop_batch(max_items, max_bytes, max_latency) => batch,
+                    _ = stopped.changed() => break,
+                };
 
-    // If the log stream is too slow, send the logs at least once a second.
-    tokio::task::spawn(async move {
-        loop {
-            let duration = tokio::time::Duration::from_millis(1_000);
-            let mut guard = s_log_lines.lock().await;
-            let log_lines = guard.deref().clone();
+                if !batch.is_empty() {
+                    uploader.send_logs(&batch).await;
+                }
+            }
 
-            // Clear buffer.
-            guard.deref_mut().clear();
+            loop {
+                let batch = log_lines.pop(max_items, max_bytes);
 
-            // Drop mutex as quickly as possible.
-            drop(guard);
+                if batch.is_empty() {
+                    break;
+                }
 
-            if log_lines.len() > 0 {
-                let _ = send_logs(&log_lines).await;
+                uploader.send_logs(&batch).await;
             }
+        }));
+    }
 
-            tokio::time::sleep(duration).await;
-        }
-    });
-
-    for log_file in log_files {
-        let log_lines = log_lines.clone();
-        let hostname = gethostname::gethostname();
-        let tags = HashMap::from([
-            ("filename".to_string(), log_file.to_string()),
-            (
-                "hostname".to_string(),
-                hostname.into_string().unwrap_or("unknown".to_string()),
-            ),
-        ]);
+    // Agent self-metrics (agent.*)
+    {
+        let queue = metrics.clone();
+        let self_metrics = self_metrics.clone();
+        let tags = global_tags.clone();
 
         tokio::task::spawn(async move {
-            let mut offset = 0u64;
-            let mut last_modified: Option<std::time::SystemTime> = None;
-            let duration = tokio::time::Duration::from_millis(1_000);
+            let duration = tokio::time::Duration::from_millis(10_000);
 
-            println!("Starting collection of logs from {}", log_file);
+            loop {
+                tokio::time::sleep(duration).await;
 
-            // Multi-line detector.
-            let regex = regex::Regex::new(r"^\d{4}-\d{2}-\d{2}.*").unwrap();
+                for metric in self_metrics.report(&tags) {
+                    queue.push(metric).await;
+                }
+            }
+        });
+    }
+
+    // Custom metrics collector
+    if config.statsd.enabled {
+        let aggregator = std::sync::Arc::new(std::sync::Mutex::new(statsd::Aggregator::new(
+            &config.statsd.percentiles,
+        )));
+        let s_aggregator = aggregator.clone();
+        let s_config = config.clone();
+        let s_tags = global_tags.clone();
+        let s_metrics = metrics.clone();
+
+        // Ship everything aggregated during the flush window in one batch.
+        tokio::task::spawn(async move {
+            let duration = tokio::time::Duration::from_millis(s_config.statsd.flush_interval_ms);
 
             loop {
-                match async_std::fs::File::open(log_file).await {
-                    Ok(f) => {
-                        // Should be okay most places (except maybe if ZFS is used...)
-                        let modified = f.metadata().await.unwrap().modified().unwrap();
-                        let mut buf = async_std::io::BufReader::new(f);
-                        match buf.seek(async_std::io::SeekFrom::Start(offset)).await {
-                            Ok(_pos) => (),
-                            Err(err) => {
-                                println!("Could not seek to: {}, err: {}", offset, err);
-                                offset = 0;
-                            }
-                        };
+                tokio::time::sleep(duration).await;
 
-                        let mut multi_line = String::new();
-
-                        loop {
-                            let mut line = String::new();
-
-                            let n = match buf.read_line(&mut line).await {
-                                Ok(n) => n,
-                                Err(err) => {
-                                    println!("Error reading file: {}", err);
-                                    0
-                                }
-                            };
-
-                            if n != 0 {
-                                // Move how far we read in the file.
-                                offset += n as u64;
-
-                                if multi_line.len() == 0 {
-                                    multi_line.push_str(&line);
-                                    // Don't check regex if it's the first line we are seeing,
-                                    // we don't know if another part of the multi-line is coming next.
-                                    continue;
-                                }
-
-                                if !regex.is_match(&line) {
-                                    // Keep buffering multi-line statement.
-                                    multi_line.push_str(&line);
-                                    continue;
-                                }
-
-                                // Maybe publish logs if we have enough of them.
-                                process_logs(&log_lines, &multi_line, &tags).await;
-
-                                // Clear multiline buffer and push in next line.
-                                multi_line.clear();
-                                multi_line.push_str(&line);
-
-                                // File appended to
-                                last_modified = Some(modified);
-                            } else {
-                                // Reached end of file, push whatever we have in the multiline buffer into the queue.
-                                process_logs(&log_lines, &multi_line, &tags).await;
-
-                                match last_modified {
-                                    Some(timestamp) => {
-                                        if timestamp < modified {
-                                            // File has been rotated
-                                            offset = 0;
-                                            last_modified = Some(modified);
-                                        }
-                                    }
-
-                                    None => {
-                                        last_modified = Some(modified);
-                                    }
-                                }
-                                break;
-                            }
-                        }
-                    }
+                let metrics = s_aggregator.lock().unwrap().flush();
 
-                    Err(err) => {
-                        println!("Can't open file {}: {}", log_file, err);
-                        break;
+                for mut metric in metrics {
+                    for (name, value) in &s_tags {
+                        metric
+                            .tags
+                            .entry(name.clone())
+                            .or_insert_with(|| value.clone());
                     }
-                };
 
-                tokio::time::sleep(duration).await;
+                    s_metrics.push(metric).await;
+                }
             }
         });
-    }
 
-    custom_metrics.await.unwrap();
-    system_metrics.await.unwrap();
-}
+        let config = config.clone();
 
-async fn process_logs(
-    log_lines: &std::sync::Arc<tokio::sync::Mutex<Vec<LogLine>>>,
-    multi_line: &String,
-    tags: &HashMap<String, String>,
-) {
-    // Push log line into publish queue.
-    let mut guard = log_lines.lock().await;
-
-    if multi_line.len() > 0 {
-        guard.deref_mut().push(LogLine {
-            line: multi_line.clone(),
-            level: None,      // TODO: parse log level
-            created_at: None, // TODO: parse timestamps
-            tags: tags.clone(),
-        });
-    }
+        tasks.push(tokio::task::spawn(async move {
+            let sock = async_std::net::UdpSocket::bind(&config.statsd.bind)
+                .await
+                .unwrap();
 
-    // Don't let the queue get too long.
-    if guard.len() >= 5 {
-        let copy = guard.deref().clone();
-        guard.deref_mut().clear();
+            println!("Listening for custom metrics on UDP {}", config.statsd.bind);
 
-        // Drop mutex as quickly as possible.
-        drop(guard);
+            // Largest possible UDP datagram.
+            let mut buf = vec![0u8; 65_535];
 
-        tokio::task::spawn(async move {
-            let _ = send_logs(&copy).await;
-        });
-    } else {
-        drop(guard);
-    }
-}
+            loop {
+                let (n, _peer) = sock.recv_from(&mut buf).await.unwrap();
 
-async fn process_metric(buf: Vec<u8>) {
-    let custom_metric = String::from_utf8_lossy(&buf).trim().to_string();
+                process_metric(&aggregator, &buf[..n]);
+            }
+        }));
+    }
 
-    let parts: Vec<_> = custom_metric.split("|").map(|x| x.to_string()).collect();
-    let name_value: Vec<_> = parts[0].split(":").map(|x| x.to_string()).collect();
+    // System metrics (system.*)
+    if config.system.enabled {
+        let config = config.clone();
+        let tags = global_tags.clone();
+        let queue = metrics.clone();
 
-    match name_value.as_slice() {
-        [name, value] => {
-            let name = name.to_string();
-            let value = value.parse::<f64>().unwrap_or(0.0);
+        tasks.push(tokio::task::spawn(async move {
+            let mut system = sysinfo::System::new_all();
+            let duration = tokio::time::Duration::from_millis(config.system.interval_ms);
 
-            let _ = send_metrics(vec![Metric {
-                name: name,
-                value: value,
-                tags: HashMap::new(),
-            }])
-            .await;
-        }
-        _ => (),
-    };
-}
+            println!("Starting collection of system metrics");
 
-async fn send_metrics(metrics: Vec<Metric>) -> reqwest::Result<()> {
-    let mut error = String::new();
+            loop {
+                tokio::time::sleep(duration).await;
+                system.refresh_all();
+
+                // Memory
+                let metrics = vec![
+                    Metric {
+                        name: "system.mem.total".to_string(),
+                        value: system.total_memory() as f64,
+                        tags: tags.clone(),
+                    },
+                    Metric {
+                        name: "system.mem.used".to_string(),
+                        value: system.used_memory() as f64,
+                        tags: tags.clone(),
+                    },
+                    Metric {
+                        name: "system.cpu.utilization".to_string(),
+                        value: system
+                            .processors()
+                            .iter()
+                            .map(|cpu| cpu.cpu_usage() as f64)
+                            .sum::<f64>()
+                            / system.processors().len() as f64,
+                        tags: tags.clone(),
+                    },
+                ];
+
+                for metric in metrics {
+                    queue.push(metric).await;
+                }
+            }
+        }));
+    }
 
-    for _ in 0..3 {
-        let api =
-            std::env::var("METRICSCAT_API_URL").unwrap_or("http://localhost:8000/api".to_string());
-        let client = reqwest::Client::new();
+    // Save log positions regularly.
+    let s_checkpoints = checkpoints.clone();
+    tokio::task::spawn(async move {
         let duration = tokio::time::Duration::from_millis(1_000);
-        //
-        // FIXME: Add suport for mTLS later.
-        //
-        // let client = match api.starts_with("https") {
-        //     // Implementing mTLS. So far this is not working, not sure why,
-        //     // I think something to do with the certificate being incorrectly formatted.
-        //     true => {
-        //         let cert_path =
-        //             std::env::var("METRICSCAT_CERTIFICATE_PATH").unwrap_or("firefox.pem".to_string());
-        //         match async_std::fs::File::open(&cert_path).await {
-        //             Ok(mut f) => {
-        //                 let mut buf = Vec::new();
-        //                 f.read_to_end(&mut buf).await.unwrap();
-        //                 match reqwest::Identity::from_pem(&buf) {
-        //                     Ok(identity) => reqwest::ClientBuilder::new()
-        //                         .identity(identity)
-        //                         .build()
-        //                         .unwrap_or(reqwest::Client::new()),
-        //                     Err(err) => {
-        //                         println!("Certificate is corrupt: {}", err);
-        //                         reqwest::Client::new()
-        //                     }
-        //                 }
-        //             }
-        //             Err(err) => {
-        //                 println!("Could not open certificate path: {}", err);
-        //                 reqwest::Client::new()
-        //             }
-        //         }
-        //     }
-
-        //     // Basic HTTP, use inside a private network only.
-        //     false => reqwest::Client::new(),
-        // };
-
-        let url = format!("{}/metrics", api);
-
-        let response = match client
-            .post(&url)
-            .body(json!(metrics).to_string())
-            .send()
-            .await
-        {
-            Ok(response) => response,
-            Err(err) => {
-                error = err.to_string();
-                tokio::time::sleep(duration).await;
-                continue;
+
+        loop {
+            tokio::time::sleep(duration).await;
+
+            if let Err(err) = s_checkpoints.save() {
+                println!("{}", err);
             }
+        }
+    });
+
+    // On shutdown, ship what's queued and in flight, then save log positions,
+    // so nothing is lost or read twice on restart.
+    let s_checkpoints = checkpoints.clone();
+    tasks.push(tokio::task::spawn(async move {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
         };
 
-        if response.status() != reqwest::StatusCode::OK {
-            error = response.text().await.unwrap_or(String::new());
-            tokio::time::sleep(duration).await;
-            continue;
+        println!("Shutting down");
+
+        // Lines are queued before their position is recorded, so the saved
+        // positions only cover lines the senders ship below.
+        s_checkpoints.freeze();
+        let _ = stop.send(true);
+
+        for sender in senders {
+            sender.await.unwrap();
         }
 
-        return Ok(());
+        if let Err(err) = s_checkpoints.save() {
+            println!("{}", err);
+        }
+
+        std::process::exit(0);
+    }));
+
+    let watcher = std::sync::Arc::new(watcher::Watcher::new());
+    let redactor = std::sync::Arc::new(Redactor::new(
+        // Validated with the rest of the config.
+        config.redact.rules().unwrap(),
+        self_metrics.clone(),
+    ));
+
+    // Files matched by the sources so far, which later sources leave alone.
+    let mut claimed = Vec::new();
+
+    for source in &config.logs {
+        // Validated with the rest of the config.
+        let exclude: Vec<_> = source
+            .exclude
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).unwrap())
+            .collect();
+
+        let mut tags = global_tags.clone();
+        tags.extend(source.tags.clone());
+
+        // Validated with the rest of the config.
+        let filter = std::sync::Arc::new(filter::Filter::new(
+            source.filter_rules().unwrap(),
+            &source.path,
+            self_metrics.clone(),
+        ));
+
+        // Lines dropped over the rate limit are replaced with a summary, so the gap shows in search.
+        if let Some(interval) = filter.summary_interval() {
+            let filter = filter.clone();
+            let tags = tags.clone();
+            let log_lines = log_lines.clone();
+
+            tokio::task::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+
+                    if let Some(summary) = filter.overflow_summary() {
+                        let log_line = LogLine {
+                            line: summary,
+                            level: Some(LogLevel::Warning),
+                            created_at: None,
+                            tags: tags.clone(),
+                            fields: HashMap::new(),
+                        };
+
+                        log_lines.push(log_line).await;
+                    }
+                }
+            });
+        }
+
+        tokio::task::spawn(
+            discovery::Discovery {
+                pattern: source.path.clone(),
+                exclude: exclude.clone(),
+                claimed: claimed.clone(),
+                rescan_interval: tokio::time::Duration::from_millis(source.rescan_interval_ms),
+                tags,
+                multiline: source.multiline_rules().unwrap(),
+                poll_interval: tokio::time::Duration::from_millis(source.poll_interval_ms),
+                // Validated with the rest of the config.
+                parser: std::sync::Arc::new(source.parser().unwrap()),
+                filter,
+                redactor: redactor.clone(),
+                inotify: source.inotify,
+                watcher: watcher.clone(),
+                log_lines: log_lines.clone(),
+                checkpoints: checkpoints.clone(),
+            }
+            .run(),
+        );
+
+        claimed.push(discovery::Claim {
+            // Validated with the rest of the config.
+            pattern: glob::Pattern::new(&source.path).unwrap(),
+            exclude,
+        });
     }
 
-    println!("Error submitting metrics after 3 attempts: {}", error);
-    Ok(())
+    for task in tasks {
+        task.await.unwrap();
+    }
 }
 
-async fn send_logs(logs: &Vec<LogLine>) -> reqwest::Result<()> {
-    let mut error = String::new();
+async fn process_logs(
+    log_lines: &Queue<LogLine>,
+    event: &str,
+    parser: &parse::LineParser,
+    filter: &filter::Filter,
+    redactor: &Redactor,
+    tags: &HashMap<String, String>,
+) {
+    if event.is_empty() {
+        return;
+    }
+
+    let parsed = parser.parse(event);
 
-    for _ in 0..3 {
-        let api =
-            std::env::var("METRICSCAT_API_URL").unwrap_or("http://localhost:8000/api".to_string());
-        let url = format!("{}/logs", api);
-        let client = reqwest::Client::new();
-        let duration = tokio::time::Duration::from_millis(1_000);
+    if !filter.keep(event, parsed.level.as_ref()) {
+        return;
+    }
 
-        let response = match client.post(&url).body(json!(logs).to_string()).send().await {
-            Ok(response) => response,
-            Err(err) => {
-                error = err.to_string();
-                tokio::time::sleep(duration).await;
-                continue;
-            }
+    // Nothing leaves the host before it's redacted.
+    let log_line = LogLine {
+        line: redactor.redact(&parsed.line),
+        level: parsed.level,
+        created_at: parsed.created_at,
+        tags: tags.clone(),
+        fields: parsed
+            .fields
+            .into_iter()
+            .map(|(key, value)| (key, redactor.redact(&value)))
+            .collect(),
+    };
+
+    log_lines.push(log_line).await;
+}
+
+fn process_metric(aggregator: &std::sync::Mutex<statsd::Aggregator>, buf: &[u8]) {
+    let packet = String::from_utf8_lossy(buf);
+    let mut samples = Vec::new();
+
+    for result in statsd::parse_packet(&packet) {
+        match result {
+            Ok(parsed) => samples.extend(parsed),
+            Err(err) => println!("Invalid custom metric: {}", err),
         };
+    }
 
-        if response.status() != reqwest::StatusCode::OK {
-            tokio::time::sleep(duration).await;
-            error = response.text().await.unwrap_or(String::new());
-            continue;
-        }
+    let mut guard = aggregator.lock().unwrap();
 
-        return Ok(());
+    for sample in samples {
+        guard.add(sample);
     }
+}
 
-    println!("Error submitting logs after 3 attempts: {}", error);
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn redacts_lines_and_field_values() {
+        let self_metrics = std::sync::Arc::new(SelfMetrics::new());
+        let log_lines = Queue::new("logs", 10, queue::Policy::Block, self_metrics.clone());
+        let parser = parse::LineParser {
+            format: parse::Format::Logfmt(parse::StructuredKeys::default()),
+            timestamp: None,
+            levels: parse::LevelDetector::new(&HashMap::new()).unwrap(),
+        };
+        let filter = filter::Filter::new(Default::default(), "app", self_metrics.clone());
+        let redactor = Redactor::new(vec![redact::Rule::builtin("email").unwrap()], self_metrics);
+
+        process_logs(
+            &log_lines,
+            "level=info msg=\"signed up bob@example.com\" user=bob@example.com",
+            &parser,
+            &filter,
+            &redactor,
+            &HashMap::new(),
+        )
+        .await;
+
+        let log_line = log_lines.pop(1, usize::MAX).remove(0);
+
+        assert!(!log_line.line.contains("bob@example.com"));
+        assert_eq!(log_line.fields["user"], "[REDACTED]");
+    }
 }
@@ -0,0 +1,386 @@
+// Persistent log tailing positions, so restarts resume where they stopped.
+//
+// For every tailed file we remember which file it was (device and inode),
+// how far we read and a checksum of its first bytes. A file is only resumed
+// if all of them still match; otherwise it's a different file and it's read
+// from the beginning.
+
+use std::collections::HashMap;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// How many bytes from the start of the file identify it.
+const FINGERPRINT_LEN: u64 = 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub dev: u64,
+    pub ino: u64,
+    pub offset: u64,
+    pub fingerprint: u32,
+    pub fingerprint_len: u64,
+}
+
+pub struct Checkpoints {
+    path: Option<PathBuf>,
+    files: Mutex<HashMap<String, Checkpoint>>,
+    dirty: AtomicBool,
+    frozen: AtomicBool,
+}
+
+impl Checkpoints {
+    /// Load the state file at `path`. Without a path, positions are kept in memory only.
+    pub fn load(path: Option<&str>) -> Result<Checkpoints, String> {
+        let files = match path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents)
+                    .map_err(|err| format!("state file {} is corrupt: {}", path, err))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => return Err(format!("could not read state file {}: {}", path, err)),
+            },
+            None => HashMap::new(),
+        };
+
+        Ok(Checkpoints {
+            path: path.map(PathBuf::from),
+            files: Mutex::new(files),
+            dirty: AtomicBool::new(false),
+            frozen: AtomicBool::new(false),
+        })
+    }
+
+    /// The saved position of `path`, if any.
+    pub fn get(&self, path: &str) -> Option<Checkpoint> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    /// Where to start reading `file`, opened from `path`: the saved offset
+    /// if it's still the same file, the beginning otherwise.
+    pub fn resume(&self, path: &str, file: &std::fs::File) -> u64 {
+        match self.get(path) {
+            Some(checkpoint) if checkpoint.matches(file) => checkpoint.offset,
+            _ => 0,
+        }
+    }
+
+    /// Record that everything before `offset` in `file`, opened from `path`, has been processed.
+    pub fn update(&self, path: &str, file: &std::fs::File, offset: u64) {
+        let metadata = match file.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                println!("Could not stat {}: {}", path, err);
+                return;
+            }
+        };
+
+        let mut files = self.files.lock().unwrap();
+
+        if self.frozen.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let (fingerprint, fingerprint_len) = match files.get(path) {
+            Some(checkpoint) if checkpoint.offset == offset && checkpoint.ino == metadata.ino() => {
+                return;
+            }
+
+            // Same file and we already have a full fingerprint for it.
+            Some(checkpoint)
+                if checkpoint.dev == metadata.dev()
+                    && checkpoint.ino == metadata.ino()
+                    && checkpoint.fingerprint_len == FINGERPRINT_LEN =>
+            {
+                (checkpoint.fingerprint, checkpoint.fingerprint_len)
+            }
+
+            _ => match fingerprint(file, FINGERPRINT_LEN) {
+                Ok(fingerprint) => fingerprint,
+                Err(err) => {
+                    println!("Could not fingerprint {}: {}", path, err);
+                    return;
+                }
+            },
+        };
+
+        files.insert(
+            path.to_string(),
+            Checkpoint {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+                offset,
+                fingerprint,
+                fingerprint_len,
+            },
+        );
+
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget a file that is no longer tailed.
+    pub fn remove(&self, path: &str) {
+        let mut files = self.files.lock().unwrap();
+
+        if !self.frozen.load(Ordering::Relaxed) && files.remove(path).is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Stop recording positions. On shutdown, lines read after this may not
+    /// reach the server, so the saved positions must not cover them.
+    pub fn freeze(&self) {
+        let _files = self.files.lock().unwrap();
+        self.frozen.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the state file if anything changed since the last save.
+    pub fn save(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let contents = json!(*self.files.lock().unwrap()).to_string();
+        let tmp = path.with_extension("tmp");
+
+        std::fs::write(&tmp, contents)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|err| {
+                self.dirty.store(true, Ordering::Relaxed);
+                format!("could not write state file {}: {}", path.display(), err)
+            })
+    }
+}
+
+impl Checkpoint {
+    /// Is `file` the file this checkpoint was taken from?
+    pub fn matches(&self, file: &std::fs::File) -> bool {
+        let metadata = match file.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+
+        if self.dev != metadata.dev() || self.ino != metadata.ino() || self.offset > metadata.len()
+        {
+            return false;
+        }
+
+        match fingerprint(file, self.fingerprint_len) {
+            Ok((fingerprint, _)) => fingerprint == self.fingerprint,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Checksum of the first `len` bytes of the file (fewer if the file is shorter),
+/// and how many bytes it covers.
+fn fingerprint(file: &std::fs::File, len: u64) -> std::io::Result<(u32, u64)> {
+    let mut buf = vec![0u8; len as usize];
+    let mut read = 0;
+
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], read as u64)? {
+            0 => break,
+            n => read += n,
+        };
+    }
+
+    Ok((crc32fast::hash(&buf[..read]), read as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "metricscat-checkpoint-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn open(path: &std::path::Path) -> std::fs::File {
+        std::fs::File::open(path).unwrap()
+    }
+
+    #[test]
+    fn resumes_the_same_file() {
+        let dir = dir("same");
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+
+        let checkpoints = Checkpoints::load(None).unwrap();
+        assert_eq!(checkpoints.resume("app", &open(&log)), 0);
+
+        checkpoints.update("app", &open(&log), 6);
+        assert_eq!(checkpoints.resume("app", &open(&log)), 6);
+
+        // Lines appended since don't change which file it is.
+        std::fs::write(&log, "first\nsecond\nthird\n").unwrap();
+        assert_eq!(checkpoints.resume("app", &open(&log)), 6);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn starts_other_files_from_the_beginning() {
+        let dir = dir("other");
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+
+        let checkpoints = Checkpoints::load(None).unwrap();
+        checkpoints.update("app", &open(&log), 6);
+
+        // Rotated: same name, new inode.
+        std::fs::rename(&log, dir.join("app.log.1")).unwrap();
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+        assert_eq!(checkpoints.resume("app", &open(&log)), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_checkpoints() {
+        let dir = dir("mismatch");
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+
+        let checkpoints = Checkpoints::load(None).unwrap();
+        checkpoints.update("app", &open(&log), 6);
+        let checkpoint = checkpoints.get("app").unwrap();
+
+        assert!(checkpoint.matches(&open(&log)));
+        assert!(!Checkpoint {
+            dev: checkpoint.dev + 1,
+            ..checkpoint.clone()
+        }
+        .matches(&open(&log)));
+        assert!(!Checkpoint {
+            ino: checkpoint.ino + 1,
+            ..checkpoint.clone()
+        }
+        .matches(&open(&log)));
+        // Past the end of the file.
+        assert!(!Checkpoint {
+            offset: 100,
+            ..checkpoint.clone()
+        }
+        .matches(&open(&log)));
+        assert!(!Checkpoint {
+            fingerprint: checkpoint.fingerprint + 1,
+            ..checkpoint
+        }
+        .matches(&open(&log)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_rewritten_in_place() {
+        let dir = dir("rewritten");
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+
+        let checkpoints = Checkpoints::load(None).unwrap();
+        checkpoints.update("app", &open(&log), 6);
+
+        // Same inode and long enough, but different contents.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&log)
+            .unwrap()
+            .write_at(b"FIRST", 0)
+            .unwrap();
+        assert_eq!(checkpoints.resume("app", &open(&log)), 0);
+
+        // Truncated below the offset.
+        std::fs::write(&log, "fir").unwrap();
+        assert_eq!(checkpoints.resume("app", &open(&log)), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_short_files_as_they_grow() {
+        let dir = dir("short");
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\n").unwrap();
+
+        let checkpoints = Checkpoints::load(None).unwrap();
+        checkpoints.update("app", &open(&log), 6);
+        assert_eq!(checkpoints.get("app").unwrap().fingerprint_len, 6);
+
+        // Shorter than a full fingerprint, so only the bytes it covers are compared.
+        let long = format!("first\n{}\n", "x".repeat(2000));
+        std::fs::write(&log, &long).unwrap();
+        assert_eq!(checkpoints.resume("app", &open(&log)), 6);
+
+        checkpoints.update("app", &open(&log), long.len() as u64);
+        let checkpoint = checkpoints.get("app").unwrap();
+        assert_eq!(checkpoint.fingerprint_len, FINGERPRINT_LEN);
+        assert_eq!(checkpoint.offset, long.len() as u64);
+        assert_eq!(checkpoints.resume("app", &open(&log)), long.len() as u64);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn saves_and_loads_positions() {
+        let dir = dir("save");
+        let log = dir.join("app.log");
+        let state = dir.join("state.json");
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+
+        let checkpoints = Checkpoints::load(state.to_str()).unwrap();
+        checkpoints.update("app", &open(&log), 6);
+        checkpoints.save().unwrap();
+
+        assert!(state.exists());
+        assert!(!state.with_extension("tmp").exists());
+
+        let loaded = Checkpoints::load(state.to_str()).unwrap();
+        assert_eq!(loaded.get("app"), checkpoints.get("app"));
+        assert_eq!(loaded.resume("app", &open(&log)), 6);
+
+        // Nothing changed, nothing written.
+        std::fs::remove_file(&state).unwrap();
+        checkpoints.save().unwrap();
+        assert!(!state.exists());
+
+        checkpoints.remove("app");
+        checkpoints.save().unwrap();
+        assert_eq!(Checkpoints::load(state.to_str()).unwrap().get("app"), None);
+
+        std::fs::write(&state, "{").unwrap();
+        assert!(Checkpoints::load(state.to_str()).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stops_recording_positions_once_frozen() {
+        let dir = dir("frozen");
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\nsecond\n").unwrap();
+
+        let checkpoints = Checkpoints::load(None).unwrap();
+        checkpoints.update("app", &open(&log), 6);
+        checkpoints.freeze();
+        checkpoints.update("app", &open(&log), 13);
+        checkpoints.remove("app");
+
+        assert_eq!(checkpoints.resume("app", &open(&log)), 6);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
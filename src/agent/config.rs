@@ -0,0 +1,1109 @@
+// Agent configuration file.
+//
+// Example:
+//
+// server_url = "http://localhost:8000/api"
+//
+// [tags]
+// env = "production"
+//
+// [statsd]
+// bind = "0.0.0.0:1337"
+//
+// [system]
+// interval_ms = 1000
+//
+// [[logs]]
+// path = "/var/log/postgresql/postgresql-12-main.log"
+// tags = { service = "postgres" }
+
+use std::collections::HashMap;
+
+use super::filter::FilterRules;
+use super::grok::Grok;
+use super::multiline::MultilineRules;
+use super::parse::{Format, LevelDetector, LineParser, StructuredKeys, TimestampParser};
+use super::queue::Policy;
+use super::ratelimit::RateLimit;
+use super::redact::{self, Rule};
+use super::retry::RetryPolicy;
+use super::upload::Compression;
+use super::LogLevel;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Base URL of the server API, e.g. http://localhost:8000/api.
+    #[serde(default = "default_server_url")]
+    pub server_url: String,
+
+    /// Tags added to every metric and log line sent by this agent.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+
+    #[serde(default)]
+    pub statsd: StatsdConfig,
+
+    #[serde(default)]
+    pub system: SystemConfig,
+
+    #[serde(default)]
+    pub logs: Vec<LogSource>,
+
+    /// Secrets and personal data removed from log lines before they are sent.
+    #[serde(default)]
+    pub redact: RedactConfig,
+
+    #[serde(default)]
+    pub queue: QueueConfig,
+
+    #[serde(default)]
+    pub upload: UploadConfig,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Key the server knows this agent by, sent with every request.
+    /// Can also be set with METRICSCAT_API_KEY.
+    pub api_key: Option<String>,
+
+    /// Certificates for talking to the server over (mutual) TLS.
+    pub tls: Option<TlsConfig>,
+
+    /// Where to keep batches the server could not accept.
+    /// Without it, undeliverable batches are dropped.
+    pub spool: Option<SpoolConfig>,
+
+    /// Where to keep batches the server rejected for good (or any undeliverable
+    /// batch, without a spool). Without it, they are dropped.
+    pub dead_letter_dir: Option<String>,
+
+    /// Where to remember how far each log file was read, so restarts
+    /// don't ship the same lines again.
+    pub state_file: Option<String>,
+}
+
+/// UDP listener for custom metrics.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    #[serde(default = "default_statsd_bind")]
+    pub bind: String,
+
+    /// How often aggregated metrics are sent to the server.
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+
+    /// Percentiles computed for timers and histograms.
+    #[serde(default = "default_percentiles")]
+    pub percentiles: Vec<f64>,
+}
+
+/// System metrics collector (system.*).
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SystemConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+}
+
+/// Bounded queues of metrics and log lines waiting to be sent.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Most metrics, and most log lines, waiting to be sent.
+    #[serde(default = "default_queue_capacity")]
+    pub capacity: usize,
+
+    /// What to do when a queue is full: block (collectors wait), drop_oldest or drop_newest.
+    #[serde(default = "default_queue_policy")]
+    pub policy: String,
+}
+
+/// Batching and compression of requests to the server.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Compression of request bodies: gzip, zstd or none.
+    #[serde(default = "default_upload_compression")]
+    pub compression: String,
+
+    /// Most metrics or log lines sent in one request.
+    #[serde(default = "default_upload_max_batch_items")]
+    pub max_batch_items: usize,
+
+    /// Largest request body, before compression (approximately).
+    #[serde(default = "default_upload_max_batch_bytes")]
+    pub max_batch_bytes: usize,
+
+    /// How long metrics and log lines wait for a batch to fill up before being sent anyway.
+    #[serde(default = "default_interval_ms")]
+    pub max_batch_latency_ms: u64,
+}
+
+/// Retries of requests that failed for transient reasons: connection errors,
+/// timeouts, 408, 429 and 5xx responses.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts in total, including the first one.
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+
+    /// Wait after the first failure, doubling with each attempt.
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+
+    /// Longest wait between attempts, including waits asked for with Retry-After.
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+/// TLS towards the server. All paths are PEM files.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// CA the server certificate must be signed by. Without it,
+    /// the system's root certificates are trusted instead.
+    pub ca_cert: Option<String>,
+
+    /// Certificate presented to the server, which identifies this agent.
+    pub client_cert: Option<String>,
+
+    /// Private key of the client certificate, PKCS#8 or RSA.
+    pub client_key: Option<String>,
+}
+
+/// On-disk spool for undeliverable batches.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolConfig {
+    pub dir: String,
+
+    /// Oldest batches are evicted once the spool grows past this size.
+    #[serde(default = "default_spool_max_bytes")]
+    pub max_bytes: u64,
+}
+
+/// Redaction of log lines.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RedactConfig {
+    /// Built-in detectors to apply, all of them by default.
+    #[serde(default = "default_redact_builtin")]
+    pub builtin: Vec<String>,
+
+    /// Custom rules, applied after the built-in ones.
+    #[serde(default)]
+    pub rules: Vec<RedactRule>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RedactRule {
+    /// Reported in the rule tag of agent.logs.redacted.
+    pub name: String,
+
+    pub pattern: String,
+
+    /// Can refer to capture groups of the pattern, e.g. "${1}[REDACTED]".
+    #[serde(default = "default_redact_replacement")]
+    pub replacement: String,
+}
+
+/// Log files to tail.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogSource {
+    /// Path or glob pattern, e.g. /var/log/nginx/*.log or /srv/*/logs/**/*.log.
+    pub path: String,
+
+    /// Glob patterns of files to skip, e.g. /var/log/nginx/*.gz.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// How often to look for new and deleted files matching `path`.
+    #[serde(default = "default_rescan_interval_ms")]
+    pub rescan_interval_ms: u64,
+
+    /// Tags added to every line read from this file.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+
+    /// Wait for inotify events instead of polling, where the filesystem supports it.
+    #[serde(default = "default_true")]
+    pub inotify: bool,
+
+    /// How often to check the files for new lines when not using inotify.
+    #[serde(default = "default_interval_ms")]
+    pub poll_interval_ms: u64,
+
+    /// How lines are written: text, json, logfmt or grok.
+    #[serde(default = "default_format")]
+    pub format: String,
+
+    /// Patterns lines are matched against with the grok format, first match wins,
+    /// e.g. "%{NGINX_ACCESS}".
+    #[serde(default)]
+    pub grok: Vec<String>,
+
+    /// Custom grok patterns by name, usable in `grok` and each other.
+    #[serde(default)]
+    pub grok_patterns: HashMap<String, String>,
+
+    /// Key of structured lines holding the message. Defaults to message or msg.
+    pub message_key: Option<String>,
+
+    /// Key of structured lines holding the level. Defaults to level, severity or lvl.
+    pub level_key: Option<String>,
+
+    /// Key of structured lines holding the timestamp. Defaults to time, timestamp, ts or @timestamp.
+    pub time_key: Option<String>,
+
+    /// How timestamps are written: rfc3339, epoch, epoch_ms, syslog or a strftime format.
+    /// Lines without a timestamp are stamped with the time the server received them.
+    pub timestamp_format: Option<String>,
+
+    /// Regex locating the timestamp in the line (the first capture group, if any).
+    /// Defaults to the start of the line.
+    pub timestamp_pattern: Option<String>,
+
+    /// Timestamps without an offset are in the host's timezone instead of UTC.
+    #[serde(default)]
+    pub timestamp_local: bool,
+
+    /// Extra keywords recognized as log levels, e.g. { "E" = "error" }.
+    #[serde(default)]
+    pub levels: HashMap<String, String>,
+
+    /// Lines matching any of these regexes are dropped, e.g. health checks.
+    #[serde(default)]
+    pub drop_lines: Vec<String>,
+
+    /// If set, only lines matching one of these regexes are kept.
+    #[serde(default)]
+    pub keep_lines: Vec<String>,
+
+    /// Percentage of lines kept by level, e.g. { debug = 10 }. Other levels are all kept.
+    #[serde(default)]
+    pub sample: HashMap<String, f64>,
+
+    /// Most lines shipped from this source; lines over the limit are dropped.
+    pub rate_limit: Option<RateLimitConfig>,
+
+    /// Defaults to events starting with an ISO 8601 date for text,
+    /// and one event per line for structured formats and grok.
+    pub multiline: Option<MultilineConfig>,
+}
+
+/// Token bucket limiting the lines shipped from a log source.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub lines_per_second: f64,
+
+    /// Lines shipped at once after a quiet period. Defaults to lines_per_second.
+    pub burst: Option<f64>,
+
+    /// How often a line saying how many lines were dropped is shipped in their place.
+    #[serde(default = "default_rate_limit_summary_interval_ms")]
+    pub summary_interval_ms: u64,
+}
+
+/// How lines are grouped into multi-line events, e.g. stack traces.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MultilineConfig {
+    /// Lines matching this regex start a new event.
+    pub start_pattern: Option<String>,
+
+    /// Lines *not* matching `start_pattern` start a new event.
+    #[serde(default)]
+    pub negate_start: bool,
+
+    /// Lines matching this regex belong to the previous event, e.g. '^\s' or '^Caused by:'.
+    /// Takes precedence over `start_pattern`.
+    pub continuation_pattern: Option<String>,
+
+    /// Lines *not* matching `continuation_pattern` belong to the previous event.
+    #[serde(default)]
+    pub negate_continuation: bool,
+
+    /// Longer events are split.
+    #[serde(default = "default_multiline_max_lines")]
+    pub max_lines: usize,
+
+    #[serde(default = "default_multiline_max_bytes")]
+    pub max_bytes: u64,
+
+    /// The last event of a file is shipped once no line was added to it for this long.
+    #[serde(default = "default_interval_ms")]
+    pub flush_timeout_ms: u64,
+}
+
+impl Default for MultilineConfig {
+    fn default() -> Self {
+        MultilineConfig {
+            // Events start with an ISO 8601 date.
+            start_pattern: Some(r"^\d{4}-\d{2}-\d{2}".to_string()),
+            negate_start: false,
+            continuation_pattern: None,
+            negate_continuation: false,
+            max_lines: default_multiline_max_lines(),
+            max_bytes: default_multiline_max_bytes(),
+            flush_timeout_ms: default_interval_ms(),
+        }
+    }
+}
+
+impl MultilineConfig {
+    pub fn rules(&self) -> Result<MultilineRules, String> {
+        let regex = |field: &str, pattern: &Option<String>| match pattern {
+            Some(pattern) => regex::Regex::new(pattern).map(Some).map_err(|err| {
+                format!(
+                    "multiline.{}: \"{}\" is not a valid regex: {}",
+                    field, pattern, err
+                )
+            }),
+            None => Ok(None),
+        };
+
+        if self.max_lines == 0 || self.max_bytes == 0 {
+            return Err("multiline.max_lines and max_bytes must be greater than 0".to_string());
+        }
+
+        Ok(MultilineRules {
+            start: regex("start_pattern", &self.start_pattern)?,
+            negate_start: self.negate_start,
+            continuation: regex("continuation_pattern", &self.continuation_pattern)?,
+            negate_continuation: self.negate_continuation,
+            max_lines: self.max_lines,
+            max_bytes: self.max_bytes,
+            flush_timeout: std::time::Duration::from_millis(self.flush_timeout_ms),
+        })
+    }
+}
+
+impl LogSource {
+    /// Parser for the level and timestamp of lines from this source.
+    pub fn parser(&self) -> Result<LineParser, String> {
+        let timestamp = match &self.timestamp_format {
+            Some(format) => Some(TimestampParser::new(
+                format,
+                self.timestamp_pattern.as_deref(),
+                self.timestamp_local,
+            )?),
+            None => None,
+        };
+
+        let mut keys = StructuredKeys::default();
+
+        for (key, keys) in [
+            (&self.message_key, &mut keys.message),
+            (&self.level_key, &mut keys.level),
+            (&self.time_key, &mut keys.time),
+        ] {
+            if let Some(key) = key {
+                *keys = vec![key.clone()];
+            }
+        }
+
+        let format = match self.format.as_ref() {
+            "text" => Format::Text,
+            "json" => Format::Json(keys),
+            "logfmt" => Format::Logfmt(keys),
+            "grok" if self.grok.is_empty() => {
+                return Err("grok: at least one pattern is needed with the grok format".to_string())
+            }
+            "grok" => {
+                let mut patterns = Vec::new();
+
+                for (idx, pattern) in self.grok.iter().enumerate() {
+                    patterns.push(
+                        Grok::new(pattern, &self.grok_patterns)
+                            .map_err(|err| format!("grok[{}]: {}", idx, err))?,
+                    );
+                }
+
+                Format::Grok(patterns, keys)
+            }
+            format => {
+                return Err(format!(
+                    "format: \"{}\" is not text, json, logfmt or grok",
+                    format
+                ))
+            }
+        };
+
+        Ok(LineParser {
+            format,
+            timestamp,
+            levels: LevelDetector::new(&self.levels)?,
+        })
+    }
+
+    /// How lines from this source are grouped into events.
+    pub fn multiline_rules(&self) -> Result<MultilineRules, String> {
+        match &self.multiline {
+            Some(multiline) => multiline.rules(),
+            None if self.format == "text" => MultilineConfig::default().rules(),
+            // Structured and grok-parsed lines are complete on their own.
+            None => MultilineConfig {
+                start_pattern: None,
+                ..Default::default()
+            }
+            .rules(),
+        }
+    }
+
+    /// Which lines from this source are shipped.
+    pub fn filter_rules(&self) -> Result<FilterRules, String> {
+        let patterns = |name: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    regex::Regex::new(pattern).map_err(|err| {
+                        format!("{}: invalid pattern \"{}\": {}", name, pattern, err)
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+
+        let mut sample = HashMap::new();
+
+        for (level, percentage) in &self.sample {
+            let level = LogLevel::from_name(level).ok_or_else(|| {
+                format!(
+                    "sample: \"{}\" is not a log level (debug, notice, info, warning, error, fatal)",
+                    level
+                )
+            })?;
+
+            if !(0.0..=100.0).contains(percentage) {
+                return Err(format!("sample: {} must be between 0 and 100", percentage));
+            }
+
+            sample.insert(level, *percentage);
+        }
+
+        let rate_limit = match &self.rate_limit {
+            Some(rate_limit) => {
+                // NaN compares false to everything, so it has to be ruled out on its own.
+                if !rate_limit.lines_per_second.is_finite() || rate_limit.lines_per_second <= 0.0 {
+                    return Err(
+                        "rate_limit.lines_per_second: must be a number greater than 0".to_string(),
+                    );
+                }
+
+                let burst = rate_limit.burst.unwrap_or(rate_limit.lines_per_second);
+
+                if !burst.is_finite() || burst < 1.0 {
+                    return Err("rate_limit.burst: must be at least 1".to_string());
+                }
+
+                if rate_limit.summary_interval_ms == 0 {
+                    return Err(
+                        "rate_limit.summary_interval_ms: must be greater than 0".to_string()
+                    );
+                }
+
+                Some(RateLimit {
+                    lines_per_second: rate_limit.lines_per_second,
+                    burst,
+                    summary_interval: std::time::Duration::from_millis(
+                        rate_limit.summary_interval_ms,
+                    ),
+                })
+            }
+            None => None,
+        };
+
+        Ok(FilterRules {
+            drop: patterns("drop_lines", &self.drop_lines)?,
+            keep: patterns("keep_lines", &self.keep_lines)?,
+            sample,
+            rate_limit,
+        })
+    }
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            enabled: true,
+            bind: default_statsd_bind(),
+            flush_interval_ms: default_flush_interval_ms(),
+            percentiles: default_percentiles(),
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: default_queue_capacity(),
+            policy: default_queue_policy(),
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn policy(&self) -> Result<Policy, String> {
+        Policy::from_name(&self.policy).ok_or_else(|| {
+            format!(
+                "policy: \"{}\" is not block, drop_oldest or drop_newest",
+                self.policy
+            )
+        })
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            compression: default_upload_compression(),
+            max_batch_items: default_upload_max_batch_items(),
+            max_batch_bytes: default_upload_max_batch_bytes(),
+            max_batch_latency_ms: default_interval_ms(),
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn compression(&self) -> Result<Compression, String> {
+        Compression::from_name(&self.compression).ok_or_else(|| {
+            format!(
+                "compression: \"{}\" is not gzip, zstd or none",
+                self.compression
+            )
+        })
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff_ms: default_retry_initial_backoff_ms(),
+            max_backoff_ms: default_retry_max_backoff_ms(),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            initial_backoff: std::time::Duration::from_millis(self.initial_backoff_ms),
+            max_backoff: std::time::Duration::from_millis(self.max_backoff_ms),
+        }
+    }
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        RedactConfig {
+            builtin: default_redact_builtin(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl RedactConfig {
+    pub fn rules(&self) -> Result<Vec<Rule>, String> {
+        let mut rules = Vec::new();
+
+        for name in &self.builtin {
+            rules.push(Rule::builtin(name).ok_or_else(|| {
+                format!(
+                    "builtin: \"{}\" is not one of {}",
+                    name,
+                    redact::builtin_names().join(", ")
+                )
+            })?);
+        }
+
+        for (idx, rule) in self.rules.iter().enumerate() {
+            rules.push(
+                Rule::new(&rule.name, &rule.pattern, &rule.replacement)
+                    .map_err(|err| format!("rules[{}].pattern: {}", idx, err))?,
+            );
+        }
+
+        Ok(rules)
+    }
+}
+
+impl Default for SystemConfig {
+    fn default() -> Self {
+        SystemConfig {
+            enabled: true,
+            interval_ms: default_interval_ms(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_url: default_server_url(),
+            tags: HashMap::new(),
+            statsd: StatsdConfig::default(),
+            system: SystemConfig::default(),
+            logs: Vec::new(),
+            redact: RedactConfig::default(),
+            queue: QueueConfig::default(),
+            upload: UploadConfig::default(),
+            retry: RetryConfig::default(),
+            api_key: None,
+            tls: None,
+            spool: None,
+            dead_letter_dir: None,
+            state_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read and validate the configuration file at `path`.
+    pub fn load(path: &str) -> Result<Config, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("could not read config file {}: {}", path, err))?;
+
+        Config::parse(&contents).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// Parse and validate the configuration from a TOML string.
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(contents).map_err(|err| err.to_string())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.server_url.starts_with("http://") && !self.server_url.starts_with("https://") {
+            return Err(format!(
+                "server_url: \"{}\" must start with http:// or https://",
+                self.server_url
+            ));
+        }
+
+        validate_tags("tags", &self.tags)?;
+
+        if self.statsd.enabled {
+            self.statsd
+                .bind
+                .parse::<std::net::SocketAddr>()
+                .map_err(|err| {
+                    format!(
+                        "statsd.bind: \"{}\" is not a valid address: {}",
+                        self.statsd.bind, err
+                    )
+                })?;
+
+            if self.statsd.flush_interval_ms == 0 {
+                return Err("statsd.flush_interval_ms: must be greater than 0".to_string());
+            }
+
+            for percentile in &self.statsd.percentiles {
+                if !percentile.is_finite() || *percentile <= 0.0 || *percentile > 100.0 {
+                    return Err(format!(
+                        "statsd.percentiles: {} must be between 0 and 100",
+                        percentile
+                    ));
+                }
+            }
+        }
+
+        if self.system.interval_ms == 0 {
+            return Err("system.interval_ms: must be greater than 0".to_string());
+        }
+
+        for (idx, source) in self.logs.iter().enumerate() {
+            if source.path.is_empty() {
+                return Err(format!("logs[{}].path: must not be empty", idx));
+            }
+
+            if !source.path.starts_with('/') {
+                return Err(format!(
+                    "logs[{}].path: \"{}\" must be an absolute path",
+                    idx, source.path
+                ));
+            }
+
+            glob::Pattern::new(&source.path).map_err(|err| {
+                format!(
+                    "logs[{}].path: \"{}\" is not a valid pattern: {}",
+                    idx, source.path, err
+                )
+            })?;
+
+            for exclude in &source.exclude {
+                glob::Pattern::new(exclude).map_err(|err| {
+                    format!(
+                        "logs[{}].exclude: \"{}\" is not a valid pattern: {}",
+                        idx, exclude, err
+                    )
+                })?;
+            }
+
+            if source.rescan_interval_ms == 0 {
+                return Err(format!(
+                    "logs[{}].rescan_interval_ms: must be greater than 0",
+                    idx
+                ));
+            }
+
+            if source.poll_interval_ms == 0 {
+                return Err(format!(
+                    "logs[{}].poll_interval_ms: must be greater than 0",
+                    idx
+                ));
+            }
+
+            if source.timestamp_pattern.is_some() && source.timestamp_format.is_none() {
+                return Err(format!(
+                    "logs[{}].timestamp_pattern: requires timestamp_format",
+                    idx
+                ));
+            }
+
+            source
+                .parser()
+                .map_err(|err| format!("logs[{}]: {}", idx, err))?;
+
+            source
+                .multiline_rules()
+                .map_err(|err| format!("logs[{}].{}", idx, err))?;
+
+            source
+                .filter_rules()
+                .map_err(|err| format!("logs[{}].{}", idx, err))?;
+
+            validate_tags(&format!("logs[{}].tags", idx), &source.tags)?;
+        }
+
+        self.redact
+            .rules()
+            .map_err(|err| format!("redact.{}", err))?;
+
+        if self.queue.capacity == 0 {
+            return Err("queue.capacity: must be greater than 0".to_string());
+        }
+
+        self.queue
+            .policy()
+            .map_err(|err| format!("queue.{}", err))?;
+
+        self.upload
+            .compression()
+            .map_err(|err| format!("upload.{}", err))?;
+
+        for (name, value) in [
+            ("max_batch_items", self.upload.max_batch_items as u64),
+            ("max_batch_bytes", self.upload.max_batch_bytes as u64),
+            ("max_batch_latency_ms", self.upload.max_batch_latency_ms),
+        ] {
+            if value == 0 {
+                return Err(format!("upload.{}: must be greater than 0", name));
+            }
+        }
+
+        if self.retry.max_attempts == 0 {
+            return Err("retry.max_attempts: must be greater than 0".to_string());
+        }
+
+        if self.retry.initial_backoff_ms > self.retry.max_backoff_ms {
+            return Err(
+                "retry.initial_backoff_ms: must not be greater than max_backoff_ms".to_string(),
+            );
+        }
+
+        if self.api_key.as_deref() == Some("") {
+            return Err("api_key: must not be empty".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            if !self.server_url.starts_with("https://") {
+                return Err(format!(
+                    "tls: server_url \"{}\" must start with https://",
+                    self.server_url
+                ));
+            }
+
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                return Err("tls: client_cert and client_key must be set together".to_string());
+            }
+        }
+
+        if let Some(spool) = &self.spool {
+            if spool.dir.is_empty() {
+                return Err("spool.dir: must not be empty".to_string());
+            }
+
+            if spool.max_bytes == 0 {
+                return Err("spool.max_bytes: must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(state_file) = &self.state_file {
+            if state_file.is_empty() {
+                return Err("state_file: must not be empty".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_tags(field: &str, tags: &HashMap<String, String>) -> Result<(), String> {
+    for (name, value) in tags {
+        if name.is_empty() {
+            return Err(format!("{}: tag names must not be empty", field));
+        }
+
+        if value.is_empty() {
+            return Err(format!("{}.{}: tag value must not be empty", field, name));
+        }
+    }
+
+    Ok(())
+}
+
+fn default_server_url() -> String {
+    "http://localhost:8000/api".to_string()
+}
+
+fn default_statsd_bind() -> String {
+    "0.0.0.0:1337".to_string()
+}
+
+fn default_flush_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_percentiles() -> Vec<f64> {
+    vec![50.0, 90.0, 95.0, 99.0]
+}
+
+fn default_interval_ms() -> u64 {
+    1_000
+}
+
+fn default_upload_compression() -> String {
+    "gzip".to_string()
+}
+
+fn default_upload_max_batch_items() -> usize {
+    1_000
+}
+
+fn default_upload_max_batch_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_retry_max_attempts() -> u32 {
+    5
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    30_000
+}
+
+fn default_queue_capacity() -> usize {
+    10_000
+}
+
+fn default_queue_policy() -> String {
+    "block".to_string()
+}
+
+fn default_spool_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_redact_builtin() -> Vec<String> {
+    redact::builtin_names()
+        .into_iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn default_redact_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+fn default_rate_limit_summary_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_format() -> String {
+    "text".to_string()
+}
+
+fn default_multiline_max_lines() -> usize {
+    1_000
+}
+
+fn default_multiline_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_rescan_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The validation error of `contents`.
+    fn error(contents: &str) -> String {
+        Config::parse(contents).unwrap_err()
+    }
+
+    #[test]
+    fn parses_the_example_config() {
+        let config = Config::parse(include_str!("../../agent.example.toml")).unwrap();
+
+        assert_eq!(config.server_url, "http://localhost:8000/api");
+        assert_eq!(config.tags["env"], "development");
+        assert!(!config.logs.is_empty());
+    }
+
+    #[test]
+    fn defaults_everything() {
+        let config = Config::parse("").unwrap();
+
+        assert_eq!(config.server_url, default_server_url());
+        assert!(config.statsd.enabled);
+        assert_eq!(config.statsd.percentiles, default_percentiles());
+        assert!(config.logs.is_empty());
+        assert!(config.spool.is_none());
+    }
+
+    #[test]
+    fn parses_log_sources() {
+        let config = Config::parse(
+            r#"
+            [[logs]]
+            path = "/var/log/nginx/*.log"
+            exclude = ["/var/log/nginx/*.gz"]
+            format = "grok"
+            grok = ["%{NGINX_ACCESS}"]
+            tags = { service = "nginx" }
+            rate_limit = { lines_per_second = 100 }
+            "#,
+        )
+        .unwrap();
+
+        let source = &config.logs[0];
+        assert_eq!(source.path, "/var/log/nginx/*.log");
+        assert_eq!(source.exclude, ["/var/log/nginx/*.gz"]);
+        assert_eq!(source.tags["service"], "nginx");
+        assert!(source.inotify);
+
+        let rate_limit = source.filter_rules().unwrap().rate_limit.unwrap();
+        assert_eq!(rate_limit.burst, 100.0);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(error("sever_url = \"http://localhost\"").contains("unknown field"));
+        assert!(error("[statsd]\nbnid = \"0.0.0.0:1337\"").contains("unknown field"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for (contents, expected) in [
+            (
+                "server_url = \"localhost:8000\"",
+                "server_url: \"localhost:8000\" must start with http:// or https://",
+            ),
+            (
+                "[tags]\nenv = \"\"",
+                "tags.env: tag value must not be empty",
+            ),
+            (
+                "[statsd]\nbind = \"nope\"",
+                "statsd.bind: \"nope\" is not a valid address: invalid socket address syntax",
+            ),
+            (
+                "[statsd]\npercentiles = [0]",
+                "statsd.percentiles: 0 must be between 0 and 100",
+            ),
+            (
+                "[[logs]]\npath = \"var/log/app.log\"",
+                "logs[0].path: \"var/log/app.log\" must be an absolute path",
+            ),
+            (
+                "[[logs]]\npath = \"/var/log/app.log\"\ntimestamp_pattern = '^\\S+'",
+                "logs[0].timestamp_pattern: requires timestamp_format",
+            ),
+            (
+                "[[logs]]\npath = \"/var/log/app.log\"\nsample = { debug = 150 }",
+                "logs[0].sample: 150 must be between 0 and 100",
+            ),
+            (
+                "[retry]\ninitial_backoff_ms = 2000\nmax_backoff_ms = 1000",
+                "retry.initial_backoff_ms: must not be greater than max_backoff_ms",
+            ),
+            (
+                "server_url = \"http://localhost:8000/api\"\n[tls]",
+                "tls: server_url \"http://localhost:8000/api\" must start with https://",
+            ),
+            (
+                "server_url = \"https://localhost:8000/api\"\n[tls]\nclient_cert = \"agent.pem\"",
+                "tls: client_cert and client_key must be set together",
+            ),
+            ("[spool]\ndir = \"\"", "spool.dir: must not be empty"),
+        ] {
+            assert_eq!(error(contents), expected, "{}", contents);
+        }
+    }
+
+    #[test]
+    fn rejects_numbers_that_are_not_finite() {
+        let rate_limit = |rate_limit: &str| {
+            error(&format!(
+                "[[logs]]\npath = \"/var/log/app.log\"\nrate_limit = {}",
+                rate_limit
+            ))
+        };
+
+        // NaN and infinity are valid TOML floats.
+        for lines_per_second in ["nan", "inf", "0"] {
+            assert_eq!(
+                rate_limit(&format!("{{ lines_per_second = {} }}", lines_per_second)),
+                "logs[0].rate_limit.lines_per_second: must be a number greater than 0"
+            );
+        }
+
+        assert_eq!(
+            rate_limit("{ lines_per_second = 10, burst = nan }"),
+            "logs[0].rate_limit.burst: must be at least 1"
+        );
+
+        for (percentile, shown) in [("nan", "NaN"), ("inf", "inf")] {
+            assert_eq!(
+                error(&format!("[statsd]\npercentiles = [50, {}]", percentile)),
+                format!("statsd.percentiles: {} must be between 0 and 100", shown)
+            );
+        }
+    }
+}
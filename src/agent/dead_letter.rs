@@ -0,0 +1,90 @@
+// Batches the server rejected for good, or that could not be delivered
+// without a spool, are written here for inspection instead of being dropped.
+//
+// Each batch is a JSON file, `<unix ms>-<n>-<kind>.json`, which can be
+// replayed by hand once the problem is fixed, e.g.:
+//
+// curl -H "X-Api-Key: $KEY" --data-binary @1666000000000-0-logs.json \
+//     http://localhost:8000/api/logs
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::self_metrics::SelfMetrics;
+
+pub struct DeadLetter {
+    dir: Option<PathBuf>,
+    // Tells apart batches written in the same millisecond.
+    count: AtomicU64,
+    self_metrics: Arc<SelfMetrics>,
+}
+
+impl DeadLetter {
+    /// Without `dir`, batches are dropped, but still counted.
+    pub fn new(dir: Option<&str>, self_metrics: Arc<SelfMetrics>) -> Result<DeadLetter, String> {
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir).map_err(|err| {
+                format!("could not create dead letter directory {}: {}", dir, err)
+            })?;
+        }
+
+        Ok(DeadLetter {
+            dir: dir.map(PathBuf::from),
+            count: AtomicU64::new(0),
+            self_metrics,
+        })
+    }
+
+    /// Keep a batch of `kind` (metrics or logs) that could not be delivered because of `error`.
+    /// The file is written on the blocking thread pool.
+    pub async fn store(&self, kind: &str, body: Vec<u8>, error: &str) {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => {
+                println!("Dropping {}: {}", kind, error);
+                self.self_metrics
+                    .incr("agent.upload.dropped", &[("kind", kind)], 1.0);
+                return;
+            }
+        };
+
+        let path = dir.join(format!(
+            "{}-{}-{}.json",
+            chrono::Utc::now().timestamp_millis(),
+            self.count.fetch_add(1, Ordering::Relaxed),
+            kind
+        ));
+
+        let (path, written) = tokio::task::spawn_blocking(move || {
+            let written = std::fs::write(&path, body);
+            (path, written)
+        })
+        .await
+        .unwrap();
+
+        match written {
+            Ok(()) => {
+                println!(
+                    "Could not deliver {}, saved to {}: {}",
+                    kind,
+                    path.display(),
+                    error
+                );
+                self.self_metrics
+                    .incr("agent.upload.dead_lettered", &[("kind", kind)], 1.0);
+            }
+            Err(err) => {
+                println!(
+                    "Dropping {}: {} (could not save to {}: {})",
+                    kind,
+                    error,
+                    path.display(),
+                    err
+                );
+                self.self_metrics
+                    .incr("agent.upload.dropped", &[("kind", kind)], 1.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+// Discovery of log files matching a glob pattern.
+//
+// The pattern is rescanned periodically: a tailer is started for every new
+// matching file and stopped once the file is gone. A stopped tailer is waited
+// for before the next rescan, so a file that comes back never has two tailers.
+//
+// A file matched by several log sources belongs to the first of them in the
+// configuration, so it's only tailed once, with that source's settings.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use super::checkpoint::Checkpoints;
+use super::filter::Filter;
+use super::multiline::MultilineRules;
+use super::parse::LineParser;
+use super::queue::Queue;
+use super::redact::Redactor;
+use super::tailer::Tailer;
+use super::watcher::Watcher;
+use super::LogLine;
+
+/// A tailer started for a file, and how to stop it.
+struct Running {
+    stop: Arc<AtomicBool>,
+    stopping: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+/// The files a log source tails: those matching `pattern` but none of `exclude`.
+#[derive(Debug, Clone)]
+pub struct Claim {
+    pub pattern: glob::Pattern,
+    pub exclude: Vec<glob::Pattern>,
+}
+
+impl Claim {
+    fn matches(&self, path: &std::path::Path) -> bool {
+        self.pattern.matches_path(path)
+            && !self
+                .exclude
+                .iter()
+                .any(|exclude| exclude.matches_path(path))
+    }
+}
+
+pub struct Discovery {
+    pub pattern: String,
+    pub exclude: Vec<glob::Pattern>,
+    /// Files claimed by the log sources before this one, which are left to them.
+    pub claimed: Vec<Claim>,
+    pub rescan_interval: tokio::time::Duration,
+    pub tags: HashMap<String, String>,
+    pub multiline: MultilineRules,
+    pub poll_interval: tokio::time::Duration,
+    pub parser: Arc<LineParser>,
+    pub filter: Arc<Filter>,
+    pub redactor: Arc<Redactor>,
+    pub inotify: bool,
+    pub watcher: Arc<Watcher>,
+    pub log_lines: Arc<Queue<LogLine>>,
+    pub checkpoints: Arc<Checkpoints>,
+}
+
+impl Discovery {
+    pub async fn run(self) {
+        // Every file being tailed.
+        let mut tailers: HashMap<String, Running> = HashMap::new();
+
+        println!("Looking for log files matching {}", self.pattern);
+
+        loop {
+            self.rescan(&mut tailers).await;
+            tokio::time::sleep(self.rescan_interval).await;
+        }
+    }
+
+    /// Start tailing new matching files, and stop tailing the ones that are gone.
+    async fn rescan(&self, tailers: &mut HashMap<String, Running>) {
+        let pattern = self.pattern.clone();
+        let exclude = self.exclude.clone();
+        let claimed = self.claimed.clone();
+
+        let paths =
+            tokio::task::spawn_blocking(move || matching_files(&pattern, &exclude, &claimed))
+                .await
+                .unwrap();
+
+        for path in &paths {
+            if !tailers.contains_key(path) {
+                tailers.insert(path.clone(), self.start(path));
+            }
+        }
+
+        let gone: Vec<String> = tailers
+            .keys()
+            .filter(|path| !paths.contains(*path))
+            .cloned()
+            .collect();
+
+        for path in gone {
+            let tailer = tailers.remove(&path).unwrap();
+
+            tailer.stop.store(true, Ordering::Relaxed);
+            tailer.stopping.notify_one();
+
+            // Done with the file and its checkpoint before it can be picked up again.
+            let _ = tailer.task.await;
+        }
+    }
+
+    fn start(&self, path: &str) -> Running {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(Notify::new());
+
+        let mut tags = self.tags.clone();
+        tags.insert("filename".to_string(), path.to_string());
+
+        let task = tokio::task::spawn(
+            Tailer {
+                path: path.to_string(),
+                tags,
+                multiline: self.multiline.clone(),
+                poll_interval: self.poll_interval,
+                parser: self.parser.clone(),
+                filter: self.filter.clone(),
+                redactor: self.redactor.clone(),
+                inotify: self.inotify,
+                watcher: self.watcher.clone(),
+                log_lines: self.log_lines.clone(),
+                checkpoints: self.checkpoints.clone(),
+                stop: stop.clone(),
+                stopping: stopping.clone(),
+            }
+            .run(),
+        );
+
+        Running {
+            stop,
+            stopping,
+            task,
+        }
+    }
+}
+
+/// Regular files matching `pattern` and none of the `exclude` patterns,
+/// unless another source already `claimed` them.
+fn matching_files(pattern: &str, exclude: &[glob::Pattern], claimed: &[Claim]) -> HashSet<String> {
+    let entries = match glob::glob(pattern) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("Invalid log file pattern {}: {}", pattern, err);
+            return HashSet::new();
+        }
+    };
+
+    entries
+        .flatten()
+        .filter(|path| path.is_file())
+        .filter(|path| !exclude.iter().any(|exclude| exclude.matches_path(path)))
+        .filter(|path| !claimed.iter().any(|claim| claim.matches(path)))
+        .filter_map(|path| path.to_str().map(|path| path.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::parse::{Format, LevelDetector};
+    use crate::agent::queue::Policy;
+    use crate::agent::self_metrics::SelfMetrics;
+    use std::path::PathBuf;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "metricscat-discovery-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn pattern(dir: &std::path::Path, pattern: &str) -> String {
+        dir.join(pattern).to_str().unwrap().to_string()
+    }
+
+    fn claim(dir: &std::path::Path, path: &str, exclude: &[&str]) -> Claim {
+        Claim {
+            pattern: glob::Pattern::new(&pattern(dir, path)).unwrap(),
+            exclude: exclude
+                .iter()
+                .map(|exclude| glob::Pattern::new(&pattern(dir, exclude)).unwrap())
+                .collect(),
+        }
+    }
+
+    /// Names of the files in `dir` matching `path`.
+    fn names(
+        dir: &std::path::Path,
+        path: &str,
+        exclude: &[&str],
+        claimed: &[Claim],
+    ) -> Vec<String> {
+        let exclude: Vec<_> = exclude
+            .iter()
+            .map(|exclude| glob::Pattern::new(&pattern(dir, exclude)).unwrap())
+            .collect();
+
+        let mut names: Vec<_> = matching_files(&pattern(dir, path), &exclude, claimed)
+            .into_iter()
+            .map(|path| path.rsplit('/').next().unwrap().to_string())
+            .collect();
+        names.sort();
+
+        names
+    }
+
+    #[test]
+    fn matches_files_but_not_excluded_ones() {
+        let dir = dir("matching");
+
+        for name in ["app.log", "db.log", "app.log.gz"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        // Directories are never tailed, whatever their name.
+        std::fs::create_dir(dir.join("archive.log")).unwrap();
+
+        assert_eq!(names(&dir, "*.log", &[], &[]), ["app.log", "db.log"]);
+        assert_eq!(names(&dir, "*", &["*.gz"], &[]), ["app.log", "db.log"]);
+        assert_eq!(names(&dir, "*.log", &["db.*"], &[]), ["app.log"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_files_claimed_by_other_sources() {
+        let dir = dir("claimed");
+
+        for name in ["app.log", "db.log"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+
+        assert_eq!(
+            names(&dir, "*.log", &[], &[claim(&dir, "app.log", &[])]),
+            ["db.log"]
+        );
+        // Not claimed by a source that excludes it.
+        assert_eq!(
+            names(&dir, "*.log", &[], &[claim(&dir, "*.log", &["app.*"])]),
+            ["app.log"]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn discovery(dir: &std::path::Path) -> Discovery {
+        let self_metrics = Arc::new(SelfMetrics::new());
+        let path = pattern(dir, "*.log");
+
+        Discovery {
+            exclude: Vec::new(),
+            claimed: Vec::new(),
+            rescan_interval: tokio::time::Duration::from_millis(20),
+            tags: HashMap::new(),
+            multiline: MultilineRules {
+                start: None,
+                negate_start: false,
+                continuation: None,
+                negate_continuation: false,
+                max_lines: 500,
+                max_bytes: 1 << 20,
+                flush_timeout: tokio::time::Duration::ZERO,
+            },
+            poll_interval: tokio::time::Duration::from_millis(20),
+            parser: Arc::new(LineParser {
+                format: Format::Text,
+                timestamp: None,
+                levels: LevelDetector::new(&HashMap::new()).unwrap(),
+            }),
+            filter: Arc::new(Filter::new(Default::default(), &path, self_metrics.clone())),
+            redactor: Arc::new(Redactor::new(Vec::new(), self_metrics.clone())),
+            inotify: false,
+            watcher: Arc::new(Watcher::new()),
+            log_lines: Arc::new(Queue::new("logs", 100, Policy::Block, self_metrics)),
+            checkpoints: Arc::new(Checkpoints::load(None).unwrap()),
+            pattern: path,
+        }
+    }
+
+    /// The next line shipped, without its newline.
+    async fn next_line(discovery: &Discovery) -> Option<String> {
+        let batch = discovery
+            .log_lines
+            .pop_batch(1, usize::MAX, tokio::time::Duration::from_secs(5))
+            .await;
+
+        batch
+            .into_iter()
+            .next()
+            .map(|line| line.line.trim_end().to_string())
+    }
+
+    #[tokio::test]
+    async fn tails_files_while_they_exist() {
+        let dir = dir("lifecycle");
+        let discovery = discovery(&dir);
+        let mut tailers = HashMap::new();
+
+        discovery.rescan(&mut tailers).await;
+        assert!(tailers.is_empty());
+
+        let log = dir.join("app.log");
+        std::fs::write(&log, "first\n").unwrap();
+        discovery.rescan(&mut tailers).await;
+
+        assert_eq!(tailers.keys().collect::<Vec<_>>(), [log.to_str().unwrap()]);
+        assert_eq!(next_line(&discovery).await.as_deref(), Some("first"));
+
+        // Stopped, and waited for, once the file is gone.
+        std::fs::remove_file(&log).unwrap();
+        discovery.rescan(&mut tailers).await;
+        assert!(tailers.is_empty());
+
+        // A file coming back under the same name is a new file, read from the start.
+        std::fs::write(&log, "second\n").unwrap();
+        discovery.rescan(&mut tailers).await;
+
+        assert_eq!(tailers.len(), 1);
+        assert_eq!(next_line(&discovery).await.as_deref(), Some("second"));
+
+        std::fs::remove_file(&log).unwrap();
+        discovery.rescan(&mut tailers).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+// Filtering, sampling and rate limiting of log lines before they are queued for shipping.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::ratelimit::{RateLimit, RateLimiter};
+use super::self_metrics::SelfMetrics;
+use super::LogLevel;
+
+/// Which lines of a source are kept.
+#[derive(Debug, Clone, Default)]
+pub struct FilterRules {
+    /// Lines matching any of these are dropped.
+    pub drop: Vec<regex::Regex>,
+    /// If not empty, only lines matching one of these are kept.
+    pub keep: Vec<regex::Regex>,
+    /// Percentage of lines kept, by level. Other levels are always kept.
+    pub sample: HashMap<LogLevel, f64>,
+    /// Lines over the limit are dropped.
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Applies a source's rules, counting what's left out in `agent.logs.dropped`
+/// and `agent.logs.sampled_out`.
+pub struct Filter {
+    rules: FilterRules,
+    rate_limiter: Option<RateLimiter>,
+    // Reported in the source tag of the self-metrics.
+    source: String,
+    self_metrics: Arc<SelfMetrics>,
+}
+
+impl Filter {
+    pub fn new(rules: FilterRules, source: &str, self_metrics: Arc<SelfMetrics>) -> Filter {
+        Filter {
+            rate_limiter: rules.rate_limit.clone().map(RateLimiter::new),
+            rules,
+            source: source.to_string(),
+            self_metrics,
+        }
+    }
+
+    /// Whether the event should be shipped.
+    pub fn keep(&self, event: &str, level: Option<&LogLevel>) -> bool {
+        if self
+            .rules
+            .drop
+            .iter()
+            .any(|pattern| pattern.is_match(event))
+        {
+            self.dropped("drop");
+            return false;
+        }
+
+        if !self.rules.keep.is_empty()
+            && !self
+                .rules
+                .keep
+                .iter()
+                .any(|pattern| pattern.is_match(event))
+        {
+            self.dropped("keep");
+            return false;
+        }
+
+        if let Some((level, percentage)) =
+            level.and_then(|level| Some((level, self.rules.sample.get(level)?)))
+        {
+            if rand::random::<f64>() * 100.0 >= *percentage {
+                self.self_metrics.incr(
+                    "agent.logs.sampled_out",
+                    &[("source", &self.source), ("level", level.name())],
+                    1.0,
+                );
+                return false;
+            }
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if !rate_limiter.allow() {
+                self.dropped("rate_limit");
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// How often `overflow_summary` should be called, if the source is rate limited.
+    pub fn summary_interval(&self) -> Option<std::time::Duration> {
+        Some(self.rate_limiter.as_ref()?.limit.summary_interval)
+    }
+
+    /// A line saying how many lines were dropped over the rate limit since the last summary.
+    pub fn overflow_summary(&self) -> Option<String> {
+        self.overflow_summary_at(std::time::Instant::now())
+    }
+
+    fn overflow_summary_at(&self, now: std::time::Instant) -> Option<String> {
+        let (dropped, over) = self.rate_limiter.as_ref()?.take_dropped(now)?;
+
+        Some(format!(
+            "dropped {} lines from {} in the last {} seconds",
+            dropped,
+            self.source,
+            over.as_secs().max(1)
+        ))
+    }
+
+    fn dropped(&self, reason: &str) {
+        self.self_metrics.incr(
+            "agent.logs.dropped",
+            &[("source", &self.source), ("reason", reason)],
+            1.0,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn filter(rules: FilterRules) -> (Filter, Arc<SelfMetrics>) {
+        let self_metrics = Arc::new(SelfMetrics::new());
+        let filter = Filter::new(rules, "/var/log/app.log", self_metrics.clone());
+
+        (filter, self_metrics)
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<regex::Regex> {
+        patterns
+            .iter()
+            .map(|pattern| regex::Regex::new(pattern).unwrap())
+            .collect()
+    }
+
+    /// The counters reported as `name`, by the value of their `tag`.
+    fn counts(self_metrics: &SelfMetrics, name: &str, tag: &str) -> HashMap<String, f64> {
+        self_metrics
+            .report(&HashMap::new())
+            .into_iter()
+            .filter(|metric| metric.name == name)
+            .inspect(|metric| assert_eq!(metric.tags["source"], "/var/log/app.log"))
+            .map(|metric| (metric.tags[tag].clone(), metric.value))
+            .collect()
+    }
+
+    #[test]
+    fn drops_matching_lines() {
+        let (filter, self_metrics) = filter(FilterRules {
+            drop: patterns(&["healthcheck", "^DEBUG"]),
+            ..Default::default()
+        });
+
+        assert!(!filter.keep("GET /healthcheck 200", None));
+        assert!(!filter.keep("DEBUG cache miss", None));
+        assert!(filter.keep("GET /users 200", None));
+
+        assert_eq!(
+            counts(&self_metrics, "agent.logs.dropped", "reason"),
+            [("drop".to_string(), 2.0)].into()
+        );
+    }
+
+    #[test]
+    fn keeps_only_matching_lines() {
+        let (filter, self_metrics) = filter(FilterRules {
+            drop: patterns(&["healthcheck"]),
+            keep: patterns(&["^ERROR", "timeout"]),
+            ..Default::default()
+        });
+
+        assert!(filter.keep("ERROR boom", None));
+        assert!(filter.keep("request timeout", None));
+        assert!(!filter.keep("INFO started", None));
+        // Dropping wins.
+        assert!(!filter.keep("ERROR healthcheck failed", None));
+
+        assert_eq!(
+            counts(&self_metrics, "agent.logs.dropped", "reason"),
+            [("keep".to_string(), 1.0), ("drop".to_string(), 1.0)].into()
+        );
+    }
+
+    #[test]
+    fn samples_lines_by_level() {
+        let (filter, self_metrics) = filter(FilterRules {
+            sample: [(LogLevel::Debug, 0.0), (LogLevel::Info, 100.0)].into(),
+            ..Default::default()
+        });
+
+        for _ in 0..10 {
+            assert!(!filter.keep("cache miss", Some(&LogLevel::Debug)));
+            assert!(filter.keep("started", Some(&LogLevel::Info)));
+            // Levels without a percentage, and lines without a level, are all kept.
+            assert!(filter.keep("boom", Some(&LogLevel::Error)));
+            assert!(filter.keep("no level", None));
+        }
+
+        assert_eq!(
+            counts(&self_metrics, "agent.logs.sampled_out", "level"),
+            [("debug".to_string(), 10.0)].into()
+        );
+    }
+
+    #[test]
+    fn rate_limits_lines_and_sums_them_up() {
+        let (filter, self_metrics) = filter(FilterRules {
+            rate_limit: Some(RateLimit {
+                lines_per_second: 0.001,
+                burst: 2.0,
+                summary_interval: Duration::from_secs(10),
+            }),
+            ..Default::default()
+        });
+
+        assert_eq!(filter.summary_interval(), Some(Duration::from_secs(10)));
+        assert_eq!(filter.overflow_summary(), None);
+
+        let kept = (0..5).filter(|_| filter.keep("line", None)).count();
+        assert_eq!(kept, 2);
+
+        assert_eq!(
+            filter.overflow_summary_at(Instant::now() + Duration::from_secs(10)),
+            Some("dropped 3 lines from /var/log/app.log in the last 10 seconds".to_string())
+        );
+        assert_eq!(filter.overflow_summary(), None);
+
+        assert_eq!(
+            counts(&self_metrics, "agent.logs.dropped", "reason"),
+            [("rate_limit".to_string(), 3.0)].into()
+        );
+    }
+}
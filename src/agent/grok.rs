@@ -0,0 +1,382 @@
+// Grok-style patterns: regexes built from named building blocks, e.g.
+// `%{IP:client} %{WORD:method} %{NOTSPACE:path}`, extracting fields from
+// unstructured lines.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Built-in patterns, usable by name from any pattern.
+const PATTERNS: &[(&str, &str)] = &[
+    // Building blocks.
+    ("USERNAME", r"[a-zA-Z0-9._-]+"),
+    ("USER", r"%{USERNAME}"),
+    ("INT", r"[+-]?\d+"),
+    ("POSINT", r"[1-9]\d*"),
+    ("NONNEGINT", r"\d+"),
+    ("NUMBER", r"[+-]?(?:\d+(?:\.\d*)?|\.\d+)"),
+    ("BASE16NUM", r"(?:0[xX])?[0-9A-Fa-f]+"),
+    ("WORD", r"\w+"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*""#),
+    ("QS", r"%{QUOTEDSTRING}"),
+    (
+        "UUID",
+        r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}",
+    ),
+    (
+        "IPV4",
+        r"(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)",
+    ),
+    // Loose, but enough to tell addresses apart from the rest of the line.
+    (
+        "IPV6",
+        r"(?:[0-9A-Fa-f]{0,4}:){2,7}[0-9A-Fa-f]{0,4}(?:%\w+)?",
+    ),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    (
+        "HOSTNAME",
+        r"[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?",
+    ),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("HOSTPORT", r"%{IPORHOST}:%{POSINT}"),
+    ("URIPATH", r"(?:/[^\s?#]*)+"),
+    ("URIPARAM", r"\?\S*"),
+    ("URIPATHPARAM", r"%{URIPATH}(?:%{URIPARAM})?"),
+    ("URI", r"[A-Za-z][A-Za-z0-9+.-]*://\S+"),
+    (
+        "LOGLEVEL",
+        r"(?i:trace|debug\d?|notice|info|warn(?:ing)?|err(?:or)?|crit(?:ical)?|alert|emerg(?:ency)?|fatal|panic|log)",
+    ),
+    // Dates and times.
+    (
+        "MONTH",
+        r"(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)[a-z]*",
+    ),
+    ("MONTHNUM", r"(?:0?[1-9]|1[0-2])"),
+    ("MONTHDAY", r"(?:0[1-9]|[12]\d|3[01]|[1-9])"),
+    ("DAY", r"(?:Mon|Tue|Wed|Thu|Fri|Sat|Sun)[a-z]*"),
+    ("YEAR", r"\d{4}"),
+    ("HOUR", r"(?:2[0-3]|[01]?\d)"),
+    ("MINUTE", r"[0-5]\d"),
+    ("SECOND", r"(?:[0-5]?\d|60)(?:[.,]\d+)?"),
+    ("TIME", r"%{HOUR}:%{MINUTE}:%{SECOND}"),
+    ("ISO8601_TIMEZONE", r"(?:Z|[+-]%{HOUR}(?::?%{MINUTE}))"),
+    (
+        "TIMESTAMP_ISO8601",
+        r"%{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?",
+    ),
+    (
+        "HTTPDATE",
+        r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} [+-]\d{4}",
+    ),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+    // Syslog.
+    ("PROG", r"[\w./%-]+"),
+    ("SYSLOGPROG", r"%{PROG:program}(?:\[%{POSINT:pid}\])?"),
+    (
+        "SYSLOGLINE",
+        r"%{SYSLOGTIMESTAMP:timestamp} %{IPORHOST:host} %{SYSLOGPROG}: %{GREEDYDATA:message}",
+    ),
+    // Apache and nginx access logs, in the common and combined formats.
+    (
+        "COMMONAPACHELOG",
+        r#"%{IPORHOST:client} %{USER:ident} %{USER:auth} \[%{HTTPDATE:timestamp}\] "(?:%{WORD:method} %{NOTSPACE:path}(?: HTTP/%{NUMBER:http_version})?|%{DATA:request})" %{NUMBER:status} (?:%{NUMBER:bytes}|-)"#,
+    ),
+    (
+        "COMBINEDAPACHELOG",
+        r#"%{COMMONAPACHELOG} "%{DATA:referrer}" "%{DATA:agent}""#,
+    ),
+    ("NGINX_ACCESS", r"%{COMBINEDAPACHELOG}"),
+    // Error logs.
+    (
+        "APACHE_ERROR_TIME",
+        r"%{DAY} %{MONTH} %{MONTHDAY} %{TIME} %{YEAR}",
+    ),
+    (
+        "APACHE_ERROR",
+        r"\[%{APACHE_ERROR_TIME:timestamp}\] \[(?:%{WORD:module})?:%{LOGLEVEL:level}\] \[pid %{POSINT:pid}(?::tid %{NUMBER:tid})?\](?: \[client %{IPORHOST:client}(?::%{POSINT:port})?\])? %{GREEDYDATA:message}",
+    ),
+    (
+        "NGINX_ERROR_TIME",
+        r"%{YEAR}/%{MONTHNUM}/%{MONTHDAY} %{TIME}",
+    ),
+    (
+        "NGINX_ERROR",
+        r"%{NGINX_ERROR_TIME:timestamp} \[%{LOGLEVEL:level}\] %{POSINT:pid}#%{NONNEGINT:tid}: (?:\*%{NONNEGINT:connection} )?%{GREEDYDATA:message}",
+    ),
+    // Postgres with the default log_line_prefix ('%m [%p] '), optionally with user@database.
+    (
+        "POSTGRESQL",
+        r"%{TIMESTAMP_ISO8601:timestamp}(?: %{WORD:timezone})? \[%{POSINT:pid}\] (?:%{USERNAME:user}@%{USERNAME:database} )?%{LOGLEVEL:level}: +%{GREEDYDATA:message}",
+    ),
+];
+
+/// A compiled grok pattern.
+#[derive(Debug, Clone)]
+pub struct Grok {
+    regex: regex::Regex,
+    // Field name of each capture group; regex group names can't contain dots.
+    fields: Vec<String>,
+}
+
+impl Grok {
+    /// Compile `pattern`, which can use the built-in patterns and `custom` ones,
+    /// the latter taking precedence.
+    pub fn new(pattern: &str, custom: &HashMap<String, String>) -> Result<Grok, String> {
+        let mut fields = Vec::new();
+        let regex = expand(pattern, custom, &mut fields, &mut Vec::new())?;
+        let regex = regex::Regex::new(&regex)
+            .map_err(|err| format!("invalid pattern \"{}\": {}", pattern, err))?;
+
+        Ok(Grok { regex, fields })
+    }
+
+    /// Fields extracted from the line, if it matches.
+    pub fn parse(&self, line: &str) -> Option<HashMap<String, String>> {
+        let captures = self.regex.captures(line)?;
+
+        Some(
+            self.fields
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, field)| {
+                    let value = captures.name(&format!("f{}", idx))?;
+                    Some((field.clone(), value.as_str().to_string()))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Replace `%{NAME}` and `%{NAME:field}` with the regex they stand for.
+/// `expanding` holds the names being expanded, to catch patterns referencing themselves.
+fn expand(
+    pattern: &str,
+    custom: &HashMap<String, String>,
+    fields: &mut Vec<String>,
+    expanding: &mut Vec<String>,
+) -> Result<String, String> {
+    static REFERENCE: OnceLock<regex::Regex> = OnceLock::new();
+
+    // A third part, as in %{NUMBER:bytes:int}, is accepted but fields are always strings.
+    let reference = REFERENCE
+        .get_or_init(|| regex::Regex::new(r"%\{(\w+)(?::([\w.@-]+))?(?::\w+)?\}").unwrap());
+    let mut expanded = String::new();
+    let mut last = 0;
+
+    for captures in reference.captures_iter(pattern) {
+        let whole = captures.get(0).unwrap();
+        let name = &captures[1];
+
+        let definition = match custom.get(name) {
+            Some(definition) => definition.as_str(),
+            None => PATTERNS
+                .iter()
+                .find(|(builtin, _)| *builtin == name)
+                .map(|(_, definition)| *definition)
+                .ok_or_else(|| format!("unknown pattern %{{{}}}", name))?,
+        };
+
+        if expanding.iter().any(|expanding| expanding == name) {
+            return Err(format!(
+                "%{{{}}} references itself through {}",
+                name,
+                expanding
+                    .iter()
+                    .map(|name| format!("%{{{}}}", name))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ));
+        }
+
+        expanded.push_str(&pattern[last..whole.start()]);
+
+        // Numbered before the fields inside it, in the order they appear.
+        let group = captures.get(2).map(|field| {
+            fields.push(field.as_str().to_string());
+            format!("f{}", fields.len() - 1)
+        });
+
+        expanding.push(name.to_string());
+        let inner = expand(definition, custom, fields, expanding)?;
+        expanding.pop();
+
+        match group {
+            Some(group) => expanded.push_str(&format!("(?P<{}>{})", group, inner)),
+            None => expanded.push_str(&format!("(?:{})", inner)),
+        };
+
+        last = whole.end();
+    }
+
+    expanded.push_str(&pattern[last..]);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(pattern: &str, line: &str) -> HashMap<String, String> {
+        Grok::new(pattern, &HashMap::new())
+            .unwrap()
+            .parse(line)
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_combined_apache_logs() {
+        let fields = parse(
+            "%{COMBINEDAPACHELOG}",
+            r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif?x=1 HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#,
+        );
+
+        assert_eq!(fields["client"], "127.0.0.1");
+        assert_eq!(fields["ident"], "-");
+        assert_eq!(fields["auth"], "frank");
+        assert_eq!(fields["timestamp"], "10/Oct/2000:13:55:36 -0700");
+        assert_eq!(fields["method"], "GET");
+        assert_eq!(fields["path"], "/apache_pb.gif?x=1");
+        assert_eq!(fields["http_version"], "1.0");
+        assert_eq!(fields["status"], "200");
+        assert_eq!(fields["bytes"], "2326");
+        assert_eq!(fields["referrer"], "http://www.example.com/start.html");
+        assert_eq!(fields["agent"], "Mozilla/4.08 [en] (Win98; I ;Nav)");
+    }
+
+    #[test]
+    fn parses_syslog_lines() {
+        let fields = parse(
+            "%{SYSLOGLINE}",
+            "Oct  1 22:14:15 mymachine su[230]: 'su root' failed for lonvick on /dev/pts/8",
+        );
+
+        assert_eq!(fields["timestamp"], "Oct  1 22:14:15");
+        assert_eq!(fields["host"], "mymachine");
+        assert_eq!(fields["program"], "su");
+        assert_eq!(fields["pid"], "230");
+        assert_eq!(
+            fields["message"],
+            "'su root' failed for lonvick on /dev/pts/8"
+        );
+
+        // The pid is optional.
+        let fields = parse(
+            "%{SYSLOGLINE}",
+            "Oct 11 22:14:15 10.0.0.1 kernel: eth0 link up",
+        );
+
+        assert_eq!(fields["program"], "kernel");
+        assert!(!fields.contains_key("pid"));
+    }
+
+    #[test]
+    fn parses_nginx_access_logs() {
+        let fields = parse(
+            "%{NGINX_ACCESS}",
+            r#"203.0.113.7 - - [17/Oct/2022:06:25:43 +0000] "POST /api/v1/orders HTTP/1.1" 201 512 "-" "curl/7.81.0""#,
+        );
+
+        assert_eq!(fields["client"], "203.0.113.7");
+        assert_eq!(fields["auth"], "-");
+        assert_eq!(fields["timestamp"], "17/Oct/2022:06:25:43 +0000");
+        assert_eq!(fields["method"], "POST");
+        assert_eq!(fields["path"], "/api/v1/orders");
+        assert_eq!(fields["status"], "201");
+        assert_eq!(fields["bytes"], "512");
+        assert_eq!(fields["referrer"], "-");
+        assert_eq!(fields["agent"], "curl/7.81.0");
+    }
+
+    #[test]
+    fn parses_nginx_error_logs() {
+        let fields = parse(
+            "%{NGINX_ERROR}",
+            r#"2022/10/17 06:25:43 [error] 1234#1234: *56 connect() failed (111: Connection refused) while connecting to upstream, client: 203.0.113.7, server: example.com, request: "GET / HTTP/1.1", upstream: "http://127.0.0.1:8000/", host: "example.com""#,
+        );
+
+        assert_eq!(fields["timestamp"], "2022/10/17 06:25:43");
+        assert_eq!(fields["level"], "error");
+        assert_eq!(fields["pid"], "1234");
+        assert_eq!(fields["tid"], "1234");
+        assert_eq!(fields["connection"], "56");
+        assert!(fields["message"].starts_with("connect() failed (111: Connection refused)"));
+    }
+
+    #[test]
+    fn parses_apache_error_logs() {
+        let fields = parse(
+            "%{APACHE_ERROR}",
+            "[Mon Oct 17 06:25:43.123456 2022] [proxy:error] [pid 4321:tid 140245] [client 203.0.113.7:52114] AH00898: Error reading from remote server returned by /app",
+        );
+
+        assert_eq!(fields["timestamp"], "Mon Oct 17 06:25:43.123456 2022");
+        assert_eq!(fields["module"], "proxy");
+        assert_eq!(fields["level"], "error");
+        assert_eq!(fields["pid"], "4321");
+        assert_eq!(fields["tid"], "140245");
+        assert_eq!(fields["client"], "203.0.113.7");
+        assert_eq!(fields["port"], "52114");
+        assert_eq!(
+            fields["message"],
+            "AH00898: Error reading from remote server returned by /app"
+        );
+    }
+
+    #[test]
+    fn parses_postgresql_logs() {
+        let fields = parse(
+            "%{POSTGRESQL}",
+            r#"2022-10-17 06:25:43.512 UTC [27841] app@orders ERROR:  duplicate key value violates unique constraint "orders_pkey""#,
+        );
+
+        assert_eq!(fields["timestamp"], "2022-10-17 06:25:43.512");
+        assert_eq!(fields["timezone"], "UTC");
+        assert_eq!(fields["pid"], "27841");
+        assert_eq!(fields["user"], "app");
+        assert_eq!(fields["database"], "orders");
+        assert_eq!(fields["level"], "ERROR");
+        assert_eq!(
+            fields["message"],
+            r#"duplicate key value violates unique constraint "orders_pkey""#
+        );
+    }
+
+    #[test]
+    fn uses_custom_patterns_first() {
+        let custom = HashMap::from([
+            ("WORD".to_string(), r"[a-z]+".to_string()),
+            ("REQUEST_ID".to_string(), r"req-%{BASE16NUM}".to_string()),
+        ]);
+        let grok = Grok::new("%{REQUEST_ID:req.id} %{WORD:word}", &custom).unwrap();
+        let fields = grok.parse("req-00ff abc").unwrap();
+
+        assert_eq!(fields["req.id"], "req-00ff");
+        assert_eq!(fields["word"], "abc");
+        assert!(grok.parse("req-00ff ABC").is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_patterns() {
+        assert!(Grok::new("%{NOPE:x}", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_recursive_patterns() {
+        let custom = HashMap::from([
+            ("SELF".to_string(), r"a%{SELF}".to_string()),
+            // Would expand exponentially if only bounded by depth.
+            ("PING".to_string(), r"%{PONG}%{PONG}".to_string()),
+            ("PONG".to_string(), r"%{PING}%{PING}".to_string()),
+        ]);
+
+        assert!(Grok::new("%{SELF:x}", &custom).is_err());
+        assert!(Grok::new("%{PING}", &custom).is_err());
+        // Using the same pattern twice side by side is fine.
+        assert!(Grok::new("%{INT:a}-%{INT:b}", &custom).is_ok());
+    }
+}
@@ -0,0 +1,280 @@
+// Grouping of log lines into multi-line events, e.g. stack traces.
+
+use std::time::{Duration, Instant};
+
+/// How to tell where one event ends and the next one starts.
+#[derive(Debug, Clone)]
+pub struct MultilineRules {
+    /// Lines matching this start a new event (or not matching, if negated).
+    pub start: Option<regex::Regex>,
+    pub negate_start: bool,
+    /// Lines matching this belong to the current event (or not matching, if negated).
+    /// Takes precedence over `start`.
+    pub continuation: Option<regex::Regex>,
+    pub negate_continuation: bool,
+    /// Events are cut once they get this long; the rest becomes a new event.
+    pub max_lines: usize,
+    pub max_bytes: u64,
+    /// An event is complete if no line was added to it for this long.
+    pub flush_timeout: Duration,
+}
+
+impl MultilineRules {
+    fn is_continuation(&self, line: &str) -> bool {
+        if let Some(continuation) = &self.continuation {
+            return continuation.is_match(line) != self.negate_continuation;
+        }
+
+        if let Some(start) = &self.start {
+            return start.is_match(line) == self.negate_start;
+        }
+
+        // Every line is an event of its own.
+        false
+    }
+}
+
+pub struct Grouper {
+    rules: MultilineRules,
+    event: String,
+    lines: usize,
+    // Size of the event in the file, which can differ from `event` if it's not valid UTF-8.
+    bytes: u64,
+    updated_at: Instant,
+}
+
+impl Grouper {
+    pub fn new(rules: MultilineRules) -> Grouper {
+        Grouper {
+            rules,
+            event: String::new(),
+            lines: 0,
+            bytes: 0,
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Add a line, `bytes` long in the file. Returns the previous event if this line
+    /// starts a new one.
+    pub fn push(&mut self, line: &str, bytes: u64) -> Option<String> {
+        let complete = if self.event.is_empty() {
+            None
+        } else if !self.rules.is_continuation(line)
+            || self.lines >= self.rules.max_lines
+            || self.bytes + bytes > self.rules.max_bytes
+        {
+            self.flush()
+        } else {
+            None
+        };
+
+        self.event.push_str(line);
+        self.lines += 1;
+        self.bytes += bytes;
+        self.updated_at = Instant::now();
+
+        complete
+    }
+
+    /// The event being grouped, even if more lines could still be added to it.
+    pub fn flush(&mut self) -> Option<String> {
+        if self.event.is_empty() {
+            return None;
+        }
+
+        self.lines = 0;
+        self.bytes = 0;
+
+        Some(std::mem::take(&mut self.event))
+    }
+
+    /// The event being grouped, if nothing was added to it for longer than the flush timeout.
+    pub fn flush_expired(&mut self) -> Option<String> {
+        match self.time_left() {
+            Some(left) if left.is_zero() => self.flush(),
+            _ => None,
+        }
+    }
+
+    /// How long until the pending event is flushed, if there is one.
+    pub fn time_left(&self) -> Option<Duration> {
+        if self.event.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.rules
+                .flush_timeout
+                .saturating_sub(self.updated_at.elapsed()),
+        )
+    }
+
+    /// Bytes read from the file but not part of a complete event yet.
+    pub fn pending_bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(start: Option<&str>, continuation: Option<&str>) -> MultilineRules {
+        MultilineRules {
+            start: start.map(|pattern| regex::Regex::new(pattern).unwrap()),
+            negate_start: false,
+            continuation: continuation.map(|pattern| regex::Regex::new(pattern).unwrap()),
+            negate_continuation: false,
+            max_lines: 500,
+            max_bytes: 1 << 20,
+            flush_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// The events completed by pushing `lines`, then the one still pending.
+    fn group(rules: MultilineRules, lines: &[&str]) -> Vec<String> {
+        let mut grouper = Grouper::new(rules);
+        let mut events: Vec<String> = lines
+            .iter()
+            .filter_map(|line| grouper.push(line, line.len() as u64))
+            .collect();
+
+        events.extend(grouper.flush());
+        events
+    }
+
+    const TRACE: &[&str] = &[
+        "2022-01-24 ERROR boom\n",
+        "  at App.main(App.java:3)\n",
+        "Caused by: timeout\n",
+        "2022-01-24 INFO next\n",
+    ];
+
+    #[test]
+    fn makes_every_line_an_event_without_rules() {
+        assert_eq!(group(rules(None, None), TRACE), TRACE);
+    }
+
+    #[test]
+    fn starts_events_at_start_lines() {
+        assert_eq!(
+            group(rules(Some(r"^\d{4}-"), None), TRACE),
+            [TRACE[..3].concat(), TRACE[3].to_string()]
+        );
+
+        let mut negated = rules(Some(r"^(\s|Caused by:)"), None);
+        negated.negate_start = true;
+
+        assert_eq!(
+            group(negated, TRACE),
+            [TRACE[..3].concat(), TRACE[3].to_string()]
+        );
+    }
+
+    #[test]
+    fn continues_events_with_continuation_lines() {
+        assert_eq!(
+            group(rules(None, Some(r"^(\s|Caused by:)")), TRACE),
+            [TRACE[..3].concat(), TRACE[3].to_string()]
+        );
+
+        let mut negated = rules(None, Some(r"^\d{4}-"));
+        negated.negate_continuation = true;
+
+        assert_eq!(
+            group(negated, TRACE),
+            [TRACE[..3].concat(), TRACE[3].to_string()]
+        );
+    }
+
+    #[test]
+    fn prefers_continuation_over_start() {
+        // Every line matches start, but indented ones still continue the event.
+        assert_eq!(
+            group(rules(Some("."), Some(r"^\s")), TRACE),
+            [
+                TRACE[..2].concat(),
+                TRACE[2].to_string(),
+                TRACE[3].to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_events_at_max_lines() {
+        let mut rules = rules(None, Some("."));
+        rules.max_lines = 3;
+
+        assert_eq!(
+            group(rules, &["a\n", "b\n", "c\n", "d\n", "e\n"]),
+            ["a\nb\nc\n", "d\ne\n"]
+        );
+    }
+
+    #[test]
+    fn splits_events_at_max_bytes() {
+        let mut rules = rules(None, Some("."));
+        rules.max_bytes = 10;
+
+        assert_eq!(
+            group(
+                rules,
+                &["aaaa\n", "bbbb\n", "cccc\n", "a line longer than that\n"]
+            ),
+            ["aaaa\nbbbb\n", "cccc\n", "a line longer than that\n"]
+        );
+    }
+
+    #[test]
+    fn flushes_events_once_they_expire() {
+        let mut grouper = Grouper::new(rules(None, Some(r"^\s")));
+
+        assert_eq!(grouper.time_left(), None);
+        assert_eq!(grouper.flush_expired(), None);
+
+        grouper.push("ERROR boom\n", 11);
+        assert!(grouper.time_left().unwrap() > Duration::from_secs(50));
+        assert_eq!(grouper.flush_expired(), None);
+
+        let mut grouper = Grouper::new(MultilineRules {
+            flush_timeout: Duration::ZERO,
+            ..rules(None, Some(r"^\s"))
+        });
+
+        grouper.push("ERROR boom\n", 11);
+        assert_eq!(grouper.time_left(), Some(Duration::ZERO));
+        assert_eq!(grouper.flush_expired().as_deref(), Some("ERROR boom\n"));
+        assert_eq!(grouper.flush_expired(), None);
+    }
+
+    #[test]
+    fn counts_bytes_of_the_pending_event() {
+        let mut grouper = Grouper::new(rules(Some(r"^\d{4}-"), None));
+        // Sizes in the file, which invalid UTF-8 can make differ from the lines.
+        let lines = [
+            (TRACE[0], 30),
+            (TRACE[1], 26),
+            (TRACE[2], 19),
+            (TRACE[3], 21),
+        ];
+
+        let mut read = 0;
+        let mut emitted = 0;
+
+        for (line, bytes) in lines {
+            read += bytes;
+
+            if grouper.push(line, bytes).is_some() {
+                emitted = 30 + 26 + 19;
+            }
+
+            // What the tailer saves as the position: everything before the pending event.
+            assert_eq!(read - grouper.pending_bytes(), emitted);
+        }
+
+        assert_eq!(grouper.pending_bytes(), 21);
+
+        grouper.flush();
+        assert_eq!(grouper.pending_bytes(), 0);
+    }
+}
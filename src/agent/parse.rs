@@ -0,0 +1,938 @@
+// Parsing of log lines: levels, timestamps and structured (JSON, logfmt, grok) fields.
+
+use chrono::{Datelike, TimeZone};
+use std::collections::HashMap;
+
+use super::grok::Grok;
+use super::LogLevel;
+
+/// Keywords recognized as log levels out of the box.
+const DEFAULT_LEVELS: &[(&str, LogLevel)] = &[
+    ("TRACE", LogLevel::Debug),
+    ("DEBUG", LogLevel::Debug),
+    ("DEBUG1", LogLevel::Debug),
+    ("DEBUG2", LogLevel::Debug),
+    ("DEBUG3", LogLevel::Debug),
+    ("DEBUG4", LogLevel::Debug),
+    ("DEBUG5", LogLevel::Debug),
+    ("INFO", LogLevel::Info),
+    ("LOG", LogLevel::Info),
+    ("NOTICE", LogLevel::Notice),
+    ("WARN", LogLevel::Warning),
+    ("WARNING", LogLevel::Warning),
+    ("ERR", LogLevel::Error),
+    ("ERROR", LogLevel::Error),
+    ("CRIT", LogLevel::Fatal),
+    ("CRITICAL", LogLevel::Fatal),
+    ("ALERT", LogLevel::Fatal),
+    ("EMERG", LogLevel::Fatal),
+    ("FATAL", LogLevel::Fatal),
+    ("PANIC", LogLevel::Fatal),
+];
+
+/// Levels are only looked for in this many words at the start of a line,
+/// where loggers put them, so a message mentioning a keyword doesn't set one.
+const LEVEL_WORDS: usize = 8;
+
+/// Format `created_at` is sent to the server in, always UTC.
+pub const CREATED_AT_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+
+#[derive(Debug, Clone)]
+enum TimestampFormat {
+    Rfc3339,
+    Epoch,
+    EpochMillis,
+    /// `Jan 24 23:17:00`, without a year.
+    Syslog,
+    Strftime(String),
+}
+
+/// Finds and parses the timestamp of a log line.
+#[derive(Debug, Clone)]
+pub struct TimestampParser {
+    format: TimestampFormat,
+    // Locates the timestamp in the line. If it has a capture group, the first group is the timestamp.
+    pattern: Option<regex::Regex>,
+    local: bool,
+}
+
+impl TimestampParser {
+    /// `format` is one of `rfc3339`, `epoch`, `epoch_ms`, `syslog` or a strftime pattern.
+    /// `pattern` is a regex locating the timestamp in the line; without it, the timestamp
+    /// is expected at the start of the line. `local` timestamps are in the host's timezone,
+    /// otherwise they are UTC unless they say otherwise.
+    pub fn new(
+        format: &str,
+        pattern: Option<&str>,
+        local: bool,
+    ) -> Result<TimestampParser, String> {
+        let format = match format {
+            "rfc3339" => TimestampFormat::Rfc3339,
+            "epoch" => TimestampFormat::Epoch,
+            "epoch_ms" => TimestampFormat::EpochMillis,
+            "syslog" => TimestampFormat::Syslog,
+            format if format.contains('%') => TimestampFormat::Strftime(format.to_string()),
+            format => {
+                return Err(format!(
+                    "\"{}\" is not rfc3339, epoch, epoch_ms, syslog or a strftime format",
+                    format
+                ))
+            }
+        };
+
+        let pattern = match pattern {
+            Some(pattern) => Some(pattern.to_string()),
+            None => match &format {
+                TimestampFormat::Rfc3339 => Some(
+                    r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:\.\d+)?(?:Z|[+-]\d{2}:?\d{2})?"
+                        .to_string(),
+                ),
+                TimestampFormat::Epoch => Some(r"^\d{9,10}(?:\.\d+)?".to_string()),
+                TimestampFormat::EpochMillis => Some(r"^\d{12,13}".to_string()),
+                TimestampFormat::Syslog => {
+                    Some(r"^[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}".to_string())
+                }
+                // Taken from the start of the line, see `extract`.
+                TimestampFormat::Strftime(_) => None,
+            },
+        };
+
+        let pattern = match pattern {
+            Some(pattern) => Some(
+                regex::Regex::new(&pattern)
+                    .map_err(|err| format!("invalid pattern \"{}\": {}", pattern, err))?,
+            ),
+            None => None,
+        };
+
+        Ok(TimestampParser {
+            format,
+            pattern,
+            local,
+        })
+    }
+
+    /// The timestamp of the line in UTC, if it has one.
+    pub fn parse(&self, line: &str) -> Option<chrono::NaiveDateTime> {
+        self.parse_str(self.extract(line)?)
+    }
+
+    /// Parse a timestamp on its own, e.g. taken from a structured field.
+    pub fn parse_str(&self, timestamp: &str) -> Option<chrono::NaiveDateTime> {
+        let timestamp = timestamp.trim();
+
+        match &self.format {
+            TimestampFormat::Rfc3339 => {
+                let timestamp = timestamp.replacen(' ', "T", 1);
+
+                match chrono::DateTime::parse_from_rfc3339(&timestamp) {
+                    Ok(timestamp) => Some(timestamp.naive_utc()),
+                    // Without an offset.
+                    Err(_) => self.to_utc(timestamp.parse::<chrono::NaiveDateTime>().ok()?),
+                }
+            }
+
+            TimestampFormat::Epoch => {
+                let seconds = timestamp.parse::<f64>().ok()?;
+                chrono::NaiveDateTime::from_timestamp_opt(
+                    seconds.trunc() as i64,
+                    (seconds.fract() * 1e9) as u32,
+                )
+            }
+
+            TimestampFormat::EpochMillis => {
+                let millis = timestamp.parse::<i64>().ok()?;
+                chrono::NaiveDateTime::from_timestamp_opt(
+                    millis.div_euclid(1000),
+                    (millis.rem_euclid(1000) * 1_000_000) as u32,
+                )
+            }
+
+            TimestampFormat::Syslog => self.parse_syslog(timestamp, chrono::Utc::now().naive_utc()),
+
+            TimestampFormat::Strftime(format) => {
+                match chrono::DateTime::parse_from_str(timestamp, format) {
+                    Ok(timestamp) => Some(timestamp.naive_utc()),
+                    // Without an offset.
+                    Err(_) => {
+                        self.to_utc(chrono::NaiveDateTime::parse_from_str(timestamp, format).ok()?)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Syslog timestamps have no year: assume it's `now`'s year unless that puts
+    /// the timestamp in the future, which happens around new year.
+    fn parse_syslog(
+        &self,
+        timestamp: &str,
+        now: chrono::NaiveDateTime,
+    ) -> Option<chrono::NaiveDateTime> {
+        let parse = |year: i32| {
+            chrono::NaiveDateTime::parse_from_str(
+                &format!("{} {}", year, timestamp),
+                "%Y %b %e %H:%M:%S",
+            )
+            .ok()
+            .and_then(|timestamp| self.to_utc(timestamp))
+        };
+
+        match parse(now.year()) {
+            Some(timestamp) if timestamp > now + chrono::Duration::days(1) => parse(now.year() - 1),
+            timestamp => timestamp,
+        }
+    }
+
+    fn extract<'a>(&self, line: &'a str) -> Option<&'a str> {
+        match &self.pattern {
+            Some(pattern) => {
+                let captures = pattern.captures(line)?;
+                let timestamp = captures.get(1).or_else(|| captures.get(0))?;
+
+                Some(timestamp.as_str())
+            }
+
+            // As many words from the start of the line as there are in the format,
+            // e.g. `2022-01-24 23:17:00.123 UTC` for `%Y-%m-%d %H:%M:%S%.f UTC`.
+            None => {
+                let words = match &self.format {
+                    TimestampFormat::Strftime(format) => format.split_whitespace().count(),
+                    _ => 1,
+                };
+
+                let line = line.trim_start().trim_start_matches('[');
+                let end = line
+                    .char_indices()
+                    .filter(|(_, c)| c.is_whitespace())
+                    .map(|(idx, _)| idx)
+                    .nth(words - 1)
+                    .unwrap_or(line.len());
+
+                Some(line[..end].trim_end_matches(']'))
+            }
+        }
+    }
+
+    fn to_utc(&self, timestamp: chrono::NaiveDateTime) -> Option<chrono::NaiveDateTime> {
+        match self.local {
+            true => Some(
+                chrono::Local
+                    .from_local_datetime(&timestamp)
+                    .earliest()?
+                    .naive_utc(),
+            ),
+            false => Some(timestamp),
+        }
+    }
+}
+
+/// Finds the log level of a line from keywords like ERROR, `[warn]` or `LOG:`
+/// among its first words. Keywords are case-insensitive.
+#[derive(Debug, Clone)]
+pub struct LevelDetector {
+    keywords: HashMap<String, LogLevel>,
+    pattern: regex::Regex,
+}
+
+impl LevelDetector {
+    /// The built-in keywords, plus `extra` which can add new ones or override them.
+    pub fn new(extra: &HashMap<String, String>) -> Result<LevelDetector, String> {
+        let mut keywords: HashMap<String, LogLevel> = DEFAULT_LEVELS
+            .iter()
+            .map(|(keyword, level)| (keyword.to_string(), level.clone()))
+            .collect();
+
+        for (keyword, level) in extra {
+            let level = LogLevel::from_name(level).ok_or_else(|| {
+                format!(
+                    "\"{}\" is not a log level (debug, notice, info, warning, error, fatal)",
+                    level
+                )
+            })?;
+
+            keywords.insert(keyword.to_uppercase(), level);
+        }
+
+        // Longest first, so WARNING wins over WARN.
+        let mut alternatives: Vec<_> = keywords
+            .keys()
+            .map(|keyword| regex::escape(keyword))
+            .collect();
+        alternatives.sort_by_key(|keyword| std::cmp::Reverse(keyword.len()));
+
+        // A whole word, maybe in brackets or followed by a colon, so ERROR doesn't
+        // match ERRORS and LOG doesn't match format=LOG.
+        let pattern = regex::Regex::new(&format!(
+            r"(?i)^[\[(<]?({})(?:[\])>:]|$)",
+            alternatives.join("|")
+        ))
+        .map_err(|err| err.to_string())?;
+
+        Ok(LevelDetector { keywords, pattern })
+    }
+
+    /// The level of the first keyword at the start of the line.
+    pub fn detect(&self, line: &str) -> Option<LogLevel> {
+        line.split_whitespace()
+            .take(LEVEL_WORDS)
+            .find_map(|word| self.level(self.pattern.captures(word)?.get(1)?.as_str()))
+    }
+
+    /// The level a keyword stands for, e.g. taken from a structured field.
+    pub fn level(&self, keyword: &str) -> Option<LogLevel> {
+        self.keywords.get(&keyword.to_uppercase()).cloned()
+    }
+}
+
+/// Keys of structured log lines holding the message, level and timestamp.
+/// The first key present in a line is used.
+#[derive(Debug, Clone)]
+pub struct StructuredKeys {
+    pub message: Vec<String>,
+    pub level: Vec<String>,
+    pub time: Vec<String>,
+}
+
+impl Default for StructuredKeys {
+    fn default() -> Self {
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+
+        StructuredKeys {
+            message: keys(&["message", "msg"]),
+            level: keys(&["level", "severity", "lvl"]),
+            time: keys(&["time", "timestamp", "ts", "@timestamp"]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Format {
+    /// Unstructured text.
+    Text,
+    /// One JSON object per line.
+    Json(StructuredKeys),
+    /// `key=value` pairs, e.g. `level=info msg="request done" duration=12ms`.
+    Logfmt(StructuredKeys),
+    /// Unstructured lines taken apart by the first grok pattern they match.
+    Grok(Vec<Grok>, StructuredKeys),
+}
+
+/// A log event taken apart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parsed {
+    pub line: String,
+    pub level: Option<LogLevel>,
+    pub created_at: Option<String>,
+    pub fields: HashMap<String, String>,
+}
+
+/// Per-source parsing of log lines.
+#[derive(Debug, Clone)]
+pub struct LineParser {
+    pub format: Format,
+    pub timestamp: Option<TimestampParser>,
+    pub levels: LevelDetector,
+}
+
+impl LineParser {
+    /// Message, level, timestamp and fields of a (multi-)line log event, as far as they can be found.
+    /// Lines that don't match the source's format are treated as text.
+    pub fn parse(&self, event: &str) -> Parsed {
+        let fields = match &self.format {
+            Format::Text => None,
+            Format::Json(keys) => parse_json(event).map(|fields| (fields, keys)),
+            Format::Logfmt(keys) => parse_logfmt(event).map(|fields| (fields, keys)),
+            Format::Grok(patterns, keys) => patterns
+                .iter()
+                .find_map(|pattern| pattern.parse(event))
+                .map(|fields| (fields, keys)),
+        };
+
+        match fields {
+            Some((fields, keys)) => self.structured(event, fields, keys),
+            None => self.text(event),
+        }
+    }
+
+    fn text(&self, event: &str) -> Parsed {
+        let level = self.levels.detect(event);
+        let created_at = self
+            .timestamp
+            .as_ref()
+            .and_then(|parser| parser.parse(event))
+            .map(|timestamp| timestamp.format(CREATED_AT_FORMAT).to_string());
+
+        Parsed {
+            line: event.to_string(),
+            level,
+            created_at,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Lift the message, level and timestamp out of the fields; the rest stay fields.
+    fn structured(
+        &self,
+        event: &str,
+        mut fields: HashMap<String, String>,
+        keys: &StructuredKeys,
+    ) -> Parsed {
+        let line = take(&mut fields, &keys.message, |message| {
+            Some(message.to_string())
+        })
+        .unwrap_or_else(|| event.to_string());
+
+        let level = take(&mut fields, &keys.level, |level| self.levels.level(level))
+            .or_else(|| self.levels.detect(&line));
+
+        let created_at = take(&mut fields, &keys.time, |time| self.parse_time(time))
+            .map(|timestamp| timestamp.format(CREATED_AT_FORMAT).to_string());
+
+        Parsed {
+            line,
+            level,
+            created_at,
+            fields,
+        }
+    }
+
+    /// Timestamp from a structured field, in the configured format or else
+    /// in any of the usual ones (RFC 3339, seconds or milliseconds since the epoch,
+    /// and those of the built-in grok patterns).
+    fn parse_time(&self, time: &str) -> Option<chrono::NaiveDateTime> {
+        if let Some(parser) = &self.timestamp {
+            return parser.parse_str(time);
+        }
+
+        let formats: &[&str] = match time.parse::<f64>() {
+            Ok(epoch) if epoch > 1e11 => &["epoch_ms"],
+            Ok(_) => &["epoch"],
+            Err(_) => &[
+                "rfc3339",
+                // Apache and nginx access logs.
+                "%d/%b/%Y:%H:%M:%S %z",
+                // nginx error log.
+                "%Y/%m/%d %H:%M:%S",
+                // Apache error log.
+                "%a %b %d %H:%M:%S%.f %Y",
+                "syslog",
+            ],
+        };
+
+        formats.iter().find_map(|format| {
+            TimestampParser::new(format, None, false)
+                .ok()?
+                .parse_str(time)
+        })
+    }
+}
+
+/// Remove the first of `keys` present in `fields` that `convert` accepts and return the converted value.
+/// Values `convert` doesn't accept stay in `fields`.
+fn take<T>(
+    fields: &mut HashMap<String, String>,
+    keys: &[String],
+    convert: impl Fn(&str) -> Option<T>,
+) -> Option<T> {
+    for key in keys {
+        if let Some(value) = fields.get(key).and_then(|value| convert(value)) {
+            fields.remove(key);
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Fields of a JSON object, with nested objects flattened into dotted keys
+/// (`{"http": {"status": 500}}` becomes `http.status=500`).
+fn parse_json(event: &str) -> Option<HashMap<String, String>> {
+    let object = match serde_json::from_str::<serde_json::Value>(event.trim()).ok()? {
+        serde_json::Value::Object(object) => object,
+        _ => return None,
+    };
+
+    let mut fields = HashMap::new();
+    flatten("", &object, &mut fields);
+
+    Some(fields)
+}
+
+fn flatten(
+    prefix: &str,
+    object: &serde_json::Map<String, serde_json::Value>,
+    fields: &mut HashMap<String, String>,
+) {
+    for (key, value) in object {
+        let key = match prefix.is_empty() {
+            true => key.clone(),
+            false => format!("{}.{}", prefix, key),
+        };
+
+        match value {
+            serde_json::Value::Object(object) => flatten(&key, object, fields),
+            serde_json::Value::Null => (),
+            serde_json::Value::String(value) => {
+                fields.insert(key, value.clone());
+            }
+            value => {
+                fields.insert(key, value.to_string());
+            }
+        };
+    }
+}
+
+/// Fields of a logfmt line. Values can be quoted, with backslash escapes
+/// (`msg="said \"hi\""`). Lines with anything but `key=value` pairs aren't logfmt.
+fn parse_logfmt(event: &str) -> Option<HashMap<String, String>> {
+    let mut fields = HashMap::new();
+    let mut chars = event.trim().chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut key = String::new();
+
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=' && *c != '"') {
+            key.push(c);
+        }
+
+        if key.is_empty() || chars.next() != Some('=') {
+            return None;
+        }
+
+        let mut value = String::new();
+
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        'n' => value.push('\n'),
+                        't' => value.push('\t'),
+                        'r' => value.push('\r'),
+                        c @ ('"' | '\\') => value.push(c),
+                        c => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                    },
+                    c => value.push(c),
+                }
+            }
+
+            // `key="a"b` is not logfmt.
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return None;
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+
+        fields.insert(key, value);
+    }
+
+    match fields.is_empty() {
+        true => None,
+        false => Some(fields),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logfmt(line: &str) -> Option<Vec<(String, String)>> {
+        let mut fields: Vec<_> = parse_logfmt(line)?.into_iter().collect();
+        fields.sort();
+        Some(fields)
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn at(timestamp: &str) -> Option<chrono::NaiveDateTime> {
+        Some(chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f").unwrap())
+    }
+
+    fn timestamp(format: &str, pattern: Option<&str>, line: &str) -> Option<chrono::NaiveDateTime> {
+        TimestampParser::new(format, pattern, false)
+            .unwrap()
+            .parse(line)
+    }
+
+    fn levels(extra: &[(&str, &str)]) -> LevelDetector {
+        let extra = extra
+            .iter()
+            .map(|(keyword, level)| (keyword.to_string(), level.to_string()))
+            .collect();
+
+        LevelDetector::new(&extra).unwrap()
+    }
+
+    #[test]
+    fn parses_rfc3339_timestamps() {
+        assert_eq!(
+            timestamp("rfc3339", None, "2022-01-24T23:17:00.123+02:00 started"),
+            at("2022-01-24 21:17:00.123")
+        );
+        assert_eq!(
+            timestamp("rfc3339", None, "[app] 2022-01-24 23:17:00Z started"),
+            at("2022-01-24 23:17:00")
+        );
+        // Without an offset, UTC.
+        assert_eq!(
+            timestamp("rfc3339", None, "2022-01-24T23:17:00 started"),
+            at("2022-01-24 23:17:00")
+        );
+        assert_eq!(timestamp("rfc3339", None, "started yesterday"), None);
+    }
+
+    #[test]
+    fn parses_epoch_timestamps() {
+        assert_eq!(
+            timestamp("epoch", None, "1643066220 started"),
+            at("2022-01-24 23:17:00")
+        );
+        assert_eq!(
+            timestamp("epoch", None, "1643066220.5 started"),
+            at("2022-01-24 23:17:00.5")
+        );
+        assert_eq!(
+            timestamp("epoch_ms", None, "1643066220123 started"),
+            at("2022-01-24 23:17:00.123")
+        );
+        // Only at the start of the line.
+        assert_eq!(timestamp("epoch", None, "took 1643066220 ns"), None);
+    }
+
+    #[test]
+    fn parses_syslog_timestamps() {
+        let parser = TimestampParser::new("syslog", None, false).unwrap();
+        let now = at("2022-06-01 12:00:00").unwrap();
+
+        assert_eq!(
+            parser.parse_syslog("Jan 24 23:17:00", now),
+            at("2022-01-24 23:17:00")
+        );
+        assert_eq!(
+            parser.parse_syslog("Jun  1 12:00:05", now),
+            at("2022-06-01 12:00:05")
+        );
+        assert!(parser
+            .parse("Jan 24 23:17:00 web-01 sshd[42]: accepted")
+            .is_some());
+        assert_eq!(parser.parse("web-01 sshd[42]: accepted"), None);
+    }
+
+    #[test]
+    fn puts_syslog_timestamps_from_december_in_the_previous_year() {
+        let parser = TimestampParser::new("syslog", None, false).unwrap();
+        let now = at("2023-01-01 00:00:10").unwrap();
+
+        assert_eq!(
+            parser.parse_syslog("Dec 31 23:59:58", now),
+            at("2022-12-31 23:59:58")
+        );
+        assert_eq!(
+            parser.parse_syslog("Jan  1 00:00:05", now),
+            at("2023-01-01 00:00:05")
+        );
+    }
+
+    #[test]
+    fn parses_strftime_timestamps() {
+        // As many words as the format has, from the start of the line.
+        assert_eq!(
+            timestamp(
+                "%Y-%m-%d %H:%M:%S%.f UTC",
+                None,
+                "2022-01-24 23:17:00.123 UTC [42] LOG:  checkpoint starting"
+            ),
+            at("2022-01-24 23:17:00.123")
+        );
+        assert_eq!(
+            timestamp("%Y-%m-%d %H:%M:%S", None, "[2022-01-24 23:17:00] started"),
+            at("2022-01-24 23:17:00")
+        );
+        // Found by the pattern, from its first group.
+        assert_eq!(
+            timestamp(
+                "%d/%b/%Y:%H:%M:%S %z",
+                Some(r"\[([^\]]+)\]"),
+                r#"10.0.0.1 - - [24/Jan/2022:23:17:00 +0100] "GET / HTTP/1.1" 200 612"#
+            ),
+            at("2022-01-24 22:17:00")
+        );
+        assert_eq!(
+            timestamp("%Y-%m-%d %H:%M:%S", None, "started at 2022-01-24 23:17:00"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_local_timestamps() {
+        let parser = TimestampParser::new("%Y-%m-%d %H:%M:%S", None, true).unwrap();
+        let local = chrono::Local
+            .from_local_datetime(&at("2022-01-24 23:17:00").unwrap())
+            .earliest()
+            .unwrap()
+            .naive_utc();
+
+        assert_eq!(parser.parse("2022-01-24 23:17:00 started"), Some(local));
+        // Timestamps with an offset are taken as they are.
+        assert_eq!(
+            TimestampParser::new("rfc3339", None, true)
+                .unwrap()
+                .parse("2022-01-24T23:17:00Z started"),
+            at("2022-01-24 23:17:00")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_timestamp_formats() {
+        assert!(TimestampParser::new("iso", None, false).is_err());
+        assert!(TimestampParser::new("%Y", Some("("), false).is_err());
+    }
+
+    #[test]
+    fn detects_levels_from_keywords() {
+        let levels = levels(&[]);
+
+        for (line, level) in [
+            ("ERROR: connection refused", LogLevel::Error),
+            (
+                "2022-01-24 23:17:00.123 UTC [42] LOG:  checkpoint starting",
+                LogLevel::Info,
+            ),
+            (
+                "2022/01/24 23:17:00 [error] 12#0: *3 open() failed",
+                LogLevel::Error,
+            ),
+            (
+                "2022-01-24 23:17:00,123 [main] WARNING com.example.App - slow",
+                LogLevel::Warning,
+            ),
+            (
+                "Jan 24 23:17:00 web-01 app[42]: <crit> disk full",
+                LogLevel::Fatal,
+            ),
+            ("ERROR:root:failed", LogLevel::Error),
+        ] {
+            assert_eq!(levels.detect(line), Some(level), "{}", line);
+        }
+    }
+
+    #[test]
+    fn ignores_keywords_in_messages() {
+        let levels = levels(&[]);
+
+        for line in [
+            "2022-01-24 23:17:00 UTC [42] user opened the settings and then the LOG viewer",
+            "GET /export?format=LOG 200",
+            "ERRORS: 0",
+            "connection closed",
+        ] {
+            assert_eq!(levels.detect(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn detects_custom_levels() {
+        let levels = levels(&[("E", "error"), ("WARN", "info"), ("oops", "fatal")]);
+
+        assert_eq!(levels.detect("E 12:00:00 failed"), Some(LogLevel::Error));
+        assert_eq!(levels.detect("WARN deprecated"), Some(LogLevel::Info));
+        assert_eq!(levels.detect("[OOPS] lost"), Some(LogLevel::Fatal));
+        assert_eq!(levels.level("Oops"), Some(LogLevel::Fatal));
+        assert!(LevelDetector::new(&[("E".to_string(), "loud".to_string())].into()).is_err());
+    }
+
+    fn json_parser(keys: StructuredKeys) -> LineParser {
+        LineParser {
+            format: Format::Json(keys),
+            timestamp: None,
+            levels: levels(&[]),
+        }
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> HashMap<String, String> {
+        fields
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn flattens_nested_json_objects() {
+        assert_eq!(
+            parse_json(r#"{"http": {"status": 500, "req": {"path": "/a"}}, "user": "bob"}"#),
+            Some(fields(&[
+                ("http.status", "500"),
+                ("http.req.path", "/a"),
+                ("user", "bob")
+            ]))
+        );
+    }
+
+    #[test]
+    fn keeps_json_values_as_text() {
+        assert_eq!(
+            parse_json(
+                r#"{"ok": true, "took": 1.5, "tags": ["a", "b"], "empty": {}, "gone": null}"#
+            ),
+            Some(fields(&[
+                ("ok", "true"),
+                ("took", "1.5"),
+                ("tags", r#"["a","b"]"#)
+            ]))
+        );
+    }
+
+    #[test]
+    fn lifts_message_level_and_time_out_of_json() {
+        let parsed = json_parser(StructuredKeys::default()).parse(
+            r#"{"msg": "done", "level": "warn", "time": "2022-01-24T23:17:00Z", "user": "bob"}"#,
+        );
+
+        assert_eq!(
+            parsed,
+            Parsed {
+                line: "done".to_string(),
+                level: Some(LogLevel::Warning),
+                created_at: Some("2022-01-24T23:17:00.000000".to_string()),
+                fields: fields(&[("user", "bob")]),
+            }
+        );
+
+        // The first key present wins, times can be epochs.
+        let parsed = json_parser(StructuredKeys::default())
+            .parse(r#"{"message": "first", "msg": "second", "ts": 1643066220}"#);
+
+        assert_eq!(parsed.line, "first");
+        assert_eq!(
+            parsed.created_at.as_deref(),
+            Some("2022-01-24T23:17:00.000000")
+        );
+        assert_eq!(parsed.fields, fields(&[("msg", "second")]));
+    }
+
+    #[test]
+    fn lifts_custom_keys_out_of_json() {
+        let keys = StructuredKeys {
+            message: vec!["text".to_string()],
+            level: vec!["sev".to_string()],
+            time: vec!["at".to_string()],
+        };
+        let parsed = json_parser(keys)
+            .parse(r#"{"text": "done", "sev": "E", "at": "1643066220123", "msg": "other"}"#);
+
+        assert_eq!(parsed.line, "done");
+        // Not a known level, so it stays a field.
+        assert_eq!(parsed.level, None);
+        assert_eq!(
+            parsed.created_at.as_deref(),
+            Some("2022-01-24T23:17:00.123000")
+        );
+        assert_eq!(parsed.fields, fields(&[("sev", "E"), ("msg", "other")]));
+    }
+
+    #[test]
+    fn falls_back_to_the_line_for_json_without_a_message() {
+        let line = r#"{"level": "unknown", "event": "ERROR: boom"}"#;
+        let parsed = json_parser(StructuredKeys::default()).parse(line);
+
+        assert_eq!(parsed.line, line);
+        assert_eq!(parsed.level, None);
+        assert_eq!(
+            parsed.fields,
+            fields(&[("level", "unknown"), ("event", "ERROR: boom")])
+        );
+    }
+
+    #[test]
+    fn keeps_lines_that_arent_json_objects_as_text() {
+        let parser = json_parser(StructuredKeys::default());
+
+        for line in [
+            r#"ERROR: {"msg": "#,
+            r#"["ERROR", 1]"#,
+            r#""ERROR""#,
+            "42",
+            "",
+        ] {
+            assert_eq!(parse_json(line), None, "{:?} isn't a JSON object", line);
+
+            let parsed = parser.parse(line);
+            assert_eq!(parsed.line, line);
+            assert!(parsed.fields.is_empty());
+        }
+
+        assert_eq!(
+            parser.parse(r#"ERROR: {"msg": "#).level,
+            Some(LogLevel::Error)
+        );
+    }
+
+    #[test]
+    fn parses_logfmt_pairs() {
+        assert_eq!(
+            logfmt("level=info msg=done  duration=12ms\t"),
+            pairs(&[("duration", "12ms"), ("level", "info"), ("msg", "done")])
+        );
+    }
+
+    #[test]
+    fn parses_quoted_logfmt_values() {
+        assert_eq!(
+            logfmt(r#"msg="said \"hi\" to \\everyone\n" path="/a b""#),
+            pairs(&[("msg", "said \"hi\" to \\everyone\n"), ("path", "/a b")])
+        );
+        // Unknown escapes are kept as they are.
+        assert_eq!(logfmt(r#"re="\d+""#), pairs(&[("re", "\\d+")]));
+        assert_eq!(logfmt(r#"msg="""#), pairs(&[("msg", "")]));
+    }
+
+    #[test]
+    fn parses_empty_logfmt_values() {
+        assert_eq!(
+            logfmt("user= status=200"),
+            pairs(&[("status", "200"), ("user", "")])
+        );
+    }
+
+    #[test]
+    fn rejects_bare_logfmt_keys() {
+        // Plain text would otherwise parse as a list of bare keys.
+        assert_eq!(logfmt("debug level=info"), None);
+        assert_eq!(logfmt("level=info debug"), None);
+    }
+
+    #[test]
+    fn rejects_garbage_as_logfmt() {
+        for line in [
+            "",
+            "   ",
+            "Connection reset by peer",
+            "=value",
+            r#"msg="unterminated"#,
+            r#"msg="quoted"trailing"#,
+            r#""key"=value"#,
+        ] {
+            assert_eq!(logfmt(line), None, "{:?} isn't logfmt", line);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+// Bounded queues between the agent's collectors and the tasks sending to the server.
+//
+// When a queue is full, its policy decides what happens: the collector waits
+// until there's room, the oldest item is dropped to make room, or the new item
+// is dropped.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::self_metrics::SelfMetrics;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+impl Policy {
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "block" => Some(Policy::Block),
+            "drop_oldest" => Some(Policy::DropOldest),
+            "drop_newest" => Some(Policy::DropNewest),
+            _ => None,
+        }
+    }
+}
+
+/// What goes into a queue.
+pub trait Item {
+    /// Roughly how many bytes the item takes once sent, to bound batch sizes.
+    fn size(&self) -> usize;
+}
+
+struct Items<T> {
+    items: VecDeque<T>,
+    bytes: usize,
+}
+
+/// Reports its depth in `agent.queue.depth` and what it dropped in `agent.queue.dropped`.
+pub struct Queue<T> {
+    name: String,
+    capacity: usize,
+    policy: Policy,
+    items: Mutex<Items<T>>,
+    pushed: tokio::sync::Notify,
+    popped: tokio::sync::Notify,
+    self_metrics: Arc<SelfMetrics>,
+}
+
+impl<T: Item> Queue<T> {
+    pub fn new(
+        name: &str,
+        capacity: usize,
+        policy: Policy,
+        self_metrics: Arc<SelfMetrics>,
+    ) -> Queue<T> {
+        Queue {
+            name: name.to_string(),
+            capacity,
+            policy,
+            items: Mutex::new(Items {
+                items: VecDeque::new(),
+                bytes: 0,
+            }),
+            pushed: tokio::sync::Notify::new(),
+            popped: tokio::sync::Notify::new(),
+            self_metrics,
+        }
+    }
+
+    pub async fn push(&self, item: T) {
+        loop {
+            // Created before checking for room, so we can't miss a pop in between.
+            let popped = self.popped.notified();
+
+            {
+                let mut items = self.items.lock().unwrap();
+
+                if items.items.len() < self.capacity {
+                    items.bytes += item.size();
+                    items.items.push_back(item);
+                    self.depth(items.items.len());
+                    self.pushed.notify_one();
+                    return;
+                }
+
+                match self.policy {
+                    Policy::Block => (),
+                    Policy::DropOldest => {
+                        if let Some(oldest) = items.items.pop_front() {
+                            items.bytes -= oldest.size();
+                        }
+
+                        items.bytes += item.size();
+                        items.items.push_back(item);
+                        self.dropped();
+                        self.pushed.notify_one();
+                        return;
+                    }
+                    Policy::DropNewest => {
+                        self.dropped();
+                        return;
+                    }
+                };
+            }
+
+            popped.await;
+        }
+    }
+
+    /// A batch of up to `max_items` items and `max_bytes` bytes, once there are that many,
+    /// the queue is full or `linger` has elapsed. Meant for a single consumer.
+    pub async fn pop_batch(
+        &self,
+        max_items: usize,
+        max_bytes: usize,
+        linger: tokio::time::Duration,
+    ) -> Vec<T> {
+        let deadline = tokio::time::Instant::now() + linger;
+
+        loop {
+            let pushed = self.pushed.notified();
+
+            {
+                let items = self.items.lock().unwrap();
+
+                if items.items.len() >= max_items.min(self.capacity) || items.bytes >= max_bytes {
+                    break;
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, pushed).await.is_err() {
+                break;
+            }
+        }
+
+        self.pop(max_items, max_bytes)
+    }
+
+    /// Up to `max_items` items and `max_bytes` bytes, without waiting.
+    /// The batch has at least one item if the queue isn't empty, however big it is.
+    pub fn pop(&self, max_items: usize, max_bytes: usize) -> Vec<T> {
+        let mut items = self.items.lock().unwrap();
+        let mut batch = Vec::new();
+        let mut bytes = 0;
+
+        while let Some(item) = items.items.front() {
+            let size = item.size();
+
+            if batch.len() >= max_items || (!batch.is_empty() && bytes + size > max_bytes) {
+                break;
+            }
+
+            bytes += size;
+            batch.extend(items.items.pop_front());
+        }
+
+        items.bytes -= bytes;
+        self.depth(items.items.len());
+        drop(items);
+
+        if !batch.is_empty() {
+            self.popped.notify_waiters();
+        }
+
+        batch
+    }
+
+    fn depth(&self, depth: usize) {
+        self.self_metrics
+            .gauge("agent.queue.depth", &[("queue", &self.name)], depth as f64);
+    }
+
+    fn dropped(&self) {
+        self.self_metrics
+            .incr("agent.queue.dropped", &[("queue", &self.name)], 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::time::{Duration, Instant};
+
+    impl Item for &'static str {
+        fn size(&self) -> usize {
+            self.len()
+        }
+    }
+
+    fn queue(capacity: usize, policy: Policy) -> (Arc<Queue<&'static str>>, Arc<SelfMetrics>) {
+        let self_metrics = Arc::new(SelfMetrics::new());
+        let queue = Queue::new("logs", capacity, policy, self_metrics.clone());
+
+        (Arc::new(queue), self_metrics)
+    }
+
+    /// The value of the self-metric `name`, if it was reported.
+    fn reported(self_metrics: &SelfMetrics, name: &str) -> Option<f64> {
+        self_metrics
+            .report(&HashMap::new())
+            .into_iter()
+            .find(|metric| metric.name == name && metric.tags["queue"] == "logs")
+            .map(|metric| metric.value)
+    }
+
+    #[tokio::test]
+    async fn blocks_until_there_is_room() {
+        let (queue, self_metrics) = queue(2, Policy::Block);
+
+        queue.push("a").await;
+        queue.push("b").await;
+
+        let s_queue = queue.clone();
+        let mut pushed = tokio::task::spawn(async move { s_queue.push("c").await });
+
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut pushed)
+            .await
+            .is_err());
+
+        assert_eq!(queue.pop(1, usize::MAX), ["a"]);
+        tokio::time::timeout(Duration::from_secs(1), pushed)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(queue.pop(10, usize::MAX), ["b", "c"]);
+        assert_eq!(reported(&self_metrics, "agent.queue.dropped"), None);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_item_when_full() {
+        let (queue, self_metrics) = queue(2, Policy::DropOldest);
+
+        for item in ["a", "b", "c", "d"] {
+            queue.push(item).await;
+        }
+
+        assert_eq!(queue.pop(10, usize::MAX), ["c", "d"]);
+        assert_eq!(reported(&self_metrics, "agent.queue.dropped"), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn drops_the_newest_item_when_full() {
+        let (queue, self_metrics) = queue(2, Policy::DropNewest);
+
+        for item in ["a", "b", "c", "d"] {
+            queue.push(item).await;
+        }
+
+        assert_eq!(queue.pop(10, usize::MAX), ["a", "b"]);
+        assert_eq!(reported(&self_metrics, "agent.queue.dropped"), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn pops_batches_within_limits() {
+        let (queue, self_metrics) = queue(10, Policy::Block);
+
+        for item in ["aaa", "bbb", "cccccccc", "d", "e"] {
+            queue.push(item).await;
+        }
+
+        assert_eq!(reported(&self_metrics, "agent.queue.depth"), Some(5.0));
+
+        assert_eq!(queue.pop(10, 7), ["aaa", "bbb"]);
+        // Bigger than the limit on its own, still sent.
+        assert_eq!(queue.pop(10, 7), ["cccccccc"]);
+        assert_eq!(queue.pop(1, 7), ["d"]);
+        assert_eq!(queue.pop(10, 7), ["e"]);
+        assert!(queue.pop(10, 7).is_empty());
+
+        assert_eq!(reported(&self_metrics, "agent.queue.depth"), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_full_batch_until_linger() {
+        let (queue, _) = queue(10, Policy::Block);
+        let linger = Duration::from_millis(200);
+
+        queue.push("a").await;
+
+        let started = Instant::now();
+        assert_eq!(queue.pop_batch(5, usize::MAX, linger).await, ["a"]);
+        assert!(started.elapsed() >= linger);
+
+        let started = Instant::now();
+        assert!(queue.pop_batch(5, usize::MAX, linger).await.is_empty());
+        assert!(started.elapsed() >= linger);
+    }
+
+    #[tokio::test]
+    async fn returns_batches_as_soon_as_they_are_full() {
+        let (queue, _) = queue(10, Policy::Block);
+        let linger = Duration::from_secs(30);
+
+        let s_queue = queue.clone();
+        let batch =
+            tokio::task::spawn(async move { s_queue.pop_batch(3, usize::MAX, linger).await });
+
+        for item in ["a", "b", "c"] {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            queue.push(item).await;
+        }
+
+        let batch = tokio::time::timeout(Duration::from_secs(1), batch).await;
+        assert_eq!(batch.unwrap().unwrap(), ["a", "b", "c"]);
+
+        // Or by size.
+        let s_queue = queue.clone();
+        let batch = tokio::task::spawn(async move { s_queue.pop_batch(100, 6, linger).await });
+
+        queue.push("aaa").await;
+        queue.push("bbb").await;
+
+        let batch = tokio::time::timeout(Duration::from_secs(1), batch).await;
+        assert_eq!(batch.unwrap().unwrap(), ["aaa", "bbb"]);
+    }
+
+    #[tokio::test]
+    async fn returns_batches_as_soon_as_the_queue_is_full() {
+        let (queue, _) = queue(2, Policy::Block);
+        let linger = Duration::from_secs(30);
+
+        queue.push("a").await;
+        queue.push("b").await;
+
+        let batch = tokio::time::timeout(
+            Duration::from_secs(1),
+            queue.pop_batch(100, usize::MAX, linger),
+        );
+        assert_eq!(batch.await.unwrap(), ["a", "b"]);
+    }
+}
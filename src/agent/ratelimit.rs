@@ -0,0 +1,147 @@
+// Token bucket limiting how many lines per second a log source ships.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    pub lines_per_second: f64,
+    /// Lines that can be shipped at once after a quiet period.
+    pub burst: f64,
+    /// How often dropped lines are summed up in a log line of their own.
+    pub summary_interval: Duration,
+}
+
+pub struct RateLimiter {
+    pub limit: RateLimit,
+    state: Mutex<State>,
+}
+
+struct State {
+    tokens: f64,
+    refilled_at: Instant,
+    dropped: u64,
+    // When the first line since the last summary was dropped.
+    dropping_since: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter::new_at(limit, Instant::now())
+    }
+
+    fn new_at(limit: RateLimit, now: Instant) -> RateLimiter {
+        RateLimiter {
+            state: Mutex::new(State {
+                tokens: limit.burst,
+                refilled_at: now,
+                dropped: 0,
+                dropping_since: None,
+            }),
+            limit,
+        }
+    }
+
+    /// Whether one more line can be shipped; if not, it's counted as dropped.
+    pub fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        state.tokens = (state.tokens
+            + now.duration_since(state.refilled_at).as_secs_f64() * self.limit.lines_per_second)
+            .min(self.limit.burst);
+        state.refilled_at = now;
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            return true;
+        }
+
+        state.dropped += 1;
+        state.dropping_since.get_or_insert(now);
+
+        false
+    }
+
+    /// How many lines were dropped since the last call, and over how long until `now`, if any.
+    pub fn take_dropped(&self, now: Instant) -> Option<(u64, Duration)> {
+        let mut state = self.state.lock().unwrap();
+        let since = state.dropping_since.take()?;
+
+        Some((
+            std::mem::take(&mut state.dropped),
+            now.saturating_duration_since(since),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(lines_per_second: f64, burst: f64, start: Instant) -> RateLimiter {
+        RateLimiter::new_at(
+            RateLimit {
+                lines_per_second,
+                burst,
+                summary_interval: Duration::from_secs(10),
+            },
+            start,
+        )
+    }
+
+    #[test]
+    fn allows_a_burst_then_drops() {
+        let start = Instant::now();
+        let limiter = limiter(1.0, 3.0, start);
+
+        assert!((0..3).all(|_| limiter.allow_at(start)));
+        assert!(!limiter.allow_at(start));
+        assert!(!limiter.allow_at(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let start = Instant::now();
+        let limiter = limiter(10.0, 1.0, start);
+
+        assert!(limiter.allow_at(start));
+        assert!(!limiter.allow_at(start + Duration::from_millis(50)));
+        // 100ms is one more line at 10 lines per second.
+        assert!(limiter.allow_at(start + Duration::from_millis(100)));
+        assert!(!limiter.allow_at(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn caps_refills_at_the_burst() {
+        let start = Instant::now();
+        let limiter = limiter(100.0, 5.0, start);
+        let later = start + Duration::from_secs(60);
+
+        assert_eq!((0..10).filter(|_| limiter.allow_at(later)).count(), 5);
+    }
+
+    #[test]
+    fn counts_dropped_lines_since_the_last_summary() {
+        let start = Instant::now();
+        let limiter = limiter(1.0, 1.0, start);
+
+        assert!(limiter.take_dropped(start).is_none());
+
+        limiter.allow_at(start);
+        for _ in 0..4 {
+            limiter.allow_at(start + Duration::from_millis(100));
+        }
+
+        assert_eq!(
+            limiter.take_dropped(start + Duration::from_secs(3)),
+            Some((4, Duration::from_millis(2_900)))
+        );
+        assert!(limiter
+            .take_dropped(start + Duration::from_secs(4))
+            .is_none());
+    }
+}
@@ -0,0 +1,196 @@
+// When and how often failed requests to the server are retried.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    /// Longest wait between attempts, including waits asked for with Retry-After.
+    pub max_backoff: Duration,
+}
+
+/// Why a request failed.
+#[derive(Debug, Clone)]
+pub enum Failure {
+    /// Worth retrying: the server couldn't be reached, was overloaded or failed.
+    Transient {
+        error: String,
+        retry_after: Option<Duration>,
+    },
+    /// The server won't ever accept this request, e.g. 400 Bad Request.
+    Permanent(String),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Transient { error, .. } => write!(f, "{}", error),
+            Failure::Permanent(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after failed attempt number `attempt` (from 1): doubling
+    /// each time up to the cap, with jitter so agents don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_backoff);
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        // Somewhere between half and all of it.
+        backoff / 2 + backoff.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+/// Classify a request that got a response from the server.
+/// A rejected API key is transient too: batches are kept until the key is fixed.
+pub fn classify(status: reqwest::StatusCode, retry_after: Option<&str>, body: String) -> Failure {
+    let error = format!("{}: {}", status, body);
+
+    match status.as_u16() {
+        401 | 403 | 408 | 429 | 500..=599 => Failure::Transient {
+            error,
+            retry_after: retry_after
+                .and_then(|retry_after| parse_retry_after(retry_after, chrono::Utc::now())),
+        },
+        _ => Failure::Permanent(error),
+    }
+}
+
+/// Retry-After is either seconds or an HTTP date, after `now`.
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let at = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+
+    (at.with_timezone(&chrono::Utc) - now).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    fn is_transient(status: u16) -> bool {
+        let status = reqwest::StatusCode::from_u16(status).unwrap();
+
+        matches!(
+            classify(status, None, String::new()),
+            Failure::Transient { .. }
+        )
+    }
+
+    #[test]
+    fn doubles_backoffs_up_to_the_cap() {
+        let policy = policy();
+
+        for (attempt, full) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1_000),
+            (40, 1_000),
+        ] {
+            for _ in 0..100 {
+                let backoff = policy.backoff(attempt, None);
+                let full = Duration::from_millis(full);
+
+                // Jittered between half and all of it.
+                assert!(
+                    backoff >= full / 2 && backoff <= full,
+                    "attempt {}: {:?}",
+                    attempt,
+                    backoff
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn waits_as_long_as_the_server_asks_up_to_the_cap() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_millis(300))),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_secs(120))),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn retries_overload_and_server_errors() {
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert!(is_transient(status), "{} should be retried", status);
+        }
+    }
+
+    #[test]
+    fn retries_rejected_api_keys() {
+        assert!(is_transient(401));
+        assert!(is_transient(403));
+    }
+
+    #[test]
+    fn gives_up_on_other_client_errors() {
+        for status in [400, 404, 405, 413, 415, 422] {
+            assert!(!is_transient(status), "{} should not be retried", status);
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(" Wed, 21 Oct 2015 07:29:30 GMT ", now),
+            Some(Duration::from_secs(90))
+        );
+        // Already passed.
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            None
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn keeps_retry_after_of_transient_failures() {
+        let status = reqwest::StatusCode::SERVICE_UNAVAILABLE;
+
+        match classify(status, Some("3"), "busy".to_string()) {
+            Failure::Transient { error, retry_after } => {
+                assert_eq!(error, "503 Service Unavailable: busy");
+                assert_eq!(retry_after, Some(Duration::from_secs(3)));
+            }
+            failure => panic!("{:?} should be transient", failure),
+        }
+    }
+}
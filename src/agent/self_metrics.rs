@@ -0,0 +1,68 @@
+// Metrics the agent reports about itself (agent.*).
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use super::Metric;
+
+type Key = (String, BTreeMap<String, String>);
+
+#[derive(Default)]
+pub struct SelfMetrics {
+    counters: Mutex<HashMap<Key, f64>>,
+    gauges: Mutex<HashMap<Key, f64>>,
+}
+
+impl SelfMetrics {
+    pub fn new() -> SelfMetrics {
+        SelfMetrics::default()
+    }
+
+    /// Add to a counter. Counters are reported as the change since the last report.
+    pub fn incr(&self, name: &str, tags: &[(&str, &str)], by: f64) {
+        let mut guard = self.counters.lock().unwrap();
+        *guard.entry(key(name, tags)).or_insert(0.0) += by;
+    }
+
+    /// Set a gauge to its current value.
+    pub fn gauge(&self, name: &str, tags: &[(&str, &str)], value: f64) {
+        let mut guard = self.gauges.lock().unwrap();
+        guard.insert(key(name, tags), value);
+    }
+
+    /// Everything recorded since the last report, with `tags` added to every metric.
+    pub fn report(&self, tags: &HashMap<String, String>) -> Vec<Metric> {
+        let counters: Vec<_> = self.counters.lock().unwrap().drain().collect();
+        let gauges: Vec<_> = self
+            .gauges
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| (key.clone(), *value))
+            .collect();
+
+        counters
+            .into_iter()
+            .chain(gauges)
+            .map(|((name, metric_tags), value)| {
+                let mut all_tags = tags.clone();
+                all_tags.extend(metric_tags);
+
+                Metric {
+                    name,
+                    value,
+                    tags: all_tags,
+                }
+            })
+            .collect()
+    }
+}
+
+fn key(name: &str, tags: &[(&str, &str)]) -> Key {
+    (
+        name.to_string(),
+        tags.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    )
+}
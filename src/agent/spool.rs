@@ -0,0 +1,431 @@
+// On-disk spool for batches the server could not accept.
+//
+// Every batch is written to its own segment file, named after a sequence number
+// so segments replay in the order they were written. A segment is:
+//
+//   magic (4 bytes) | kind (1 byte) | payload length (u32 LE) | CRC32 of payload (u32 LE) | payload
+//
+// Segments are written to a temporary file first and renamed into place, so a crash
+// never leaves a half-written segment behind. When the spool grows past its size
+// limit, the oldest segments are evicted.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::self_metrics::SelfMetrics;
+
+const MAGIC: &[u8; 4] = b"MCS1";
+const HEADER_LEN: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Metrics,
+    Logs,
+}
+
+impl Kind {
+    fn as_byte(self) -> u8 {
+        match self {
+            Kind::Metrics => 1,
+            Kind::Logs => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Kind> {
+        match byte {
+            1 => Some(Kind::Metrics),
+            2 => Some(Kind::Logs),
+            _ => None,
+        }
+    }
+}
+
+/// Why a segment couldn't be read.
+enum ReadError {
+    /// The segment is damaged or gone and never will be readable.
+    Corrupt(String),
+    /// Reading failed, but may work later (e.g. too many open files).
+    Io(String),
+}
+
+pub struct Segment {
+    pub seq: u64,
+    pub kind: Kind,
+    pub payload: Vec<u8>,
+}
+
+struct State {
+    next_seq: u64,
+    bytes: u64,
+    // Sequence number and size on disk of every segment, oldest first.
+    segments: VecDeque<(u64, u64)>,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<State>,
+    self_metrics: Arc<SelfMetrics>,
+}
+
+impl Spool {
+    /// Open the spool in `dir`, creating it if needed and picking up
+    /// segments left over from a previous run.
+    pub fn open(
+        dir: &Path,
+        max_bytes: u64,
+        self_metrics: Arc<SelfMetrics>,
+    ) -> Result<Spool, String> {
+        std::fs::create_dir_all(dir).map_err(|err| {
+            format!(
+                "could not create spool directory {}: {}",
+                dir.display(),
+                err
+            )
+        })?;
+
+        let entries = std::fs::read_dir(dir)
+            .map_err(|err| format!("could not read spool directory {}: {}", dir.display(), err))?;
+
+        let mut segments = Vec::new();
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("seg") => (),
+                // Left over from a crash in the middle of a write.
+                Some("tmp") => {
+                    let _ = std::fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let seq = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            let size = entry.metadata().map(|metadata| metadata.len());
+
+            if let (Some(seq), Ok(size)) = (seq, size) {
+                segments.push((seq, size));
+            }
+        }
+
+        segments.sort_unstable();
+
+        let state = State {
+            next_seq: segments.last().map(|(seq, _)| seq + 1).unwrap_or(0),
+            bytes: segments.iter().map(|(_, size)| size).sum(),
+            segments: segments.into_iter().collect(),
+        };
+
+        if !state.segments.is_empty() {
+            println!(
+                "Spool {} has {} undelivered batches ({} bytes)",
+                dir.display(),
+                state.segments.len(),
+                state.bytes
+            );
+        }
+
+        let spool = Spool {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            state: Mutex::new(state),
+            self_metrics,
+        };
+        spool.report(&spool.state.lock().unwrap());
+
+        Ok(spool)
+    }
+
+    /// Persist a batch. Evicts the oldest segments if the spool would grow past its limit.
+    /// Writes and syncs a file, so async code should call it with `spawn_blocking`.
+    pub fn push(&self, kind: Kind, payload: &[u8]) -> Result<(), String> {
+        let size = (HEADER_LEN + payload.len()) as u64;
+
+        if size > self.max_bytes {
+            self.self_metrics.incr("agent.spool.oversized", &[], 1.0);
+            return Err(format!(
+                "batch of {} bytes is larger than the spool ({} bytes)",
+                size, self.max_bytes
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        while state.bytes + size > self.max_bytes {
+            let (seq, old_size) = match state.segments.pop_front() {
+                Some(segment) => segment,
+                None => break,
+            };
+
+            let _ = std::fs::remove_file(self.path(seq));
+            state.bytes -= old_size;
+            self.self_metrics.incr("agent.spool.evicted", &[], 1.0);
+        }
+
+        let seq = state.next_seq;
+        let tmp = self.dir.join(format!("{:020}.tmp", seq));
+
+        let mut buf = Vec::with_capacity(size as usize);
+        buf.extend_from_slice(MAGIC);
+        buf.push(kind.as_byte());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        buf.extend_from_slice(payload);
+
+        let written = std::fs::File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(&buf)?;
+                f.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, self.path(seq)));
+
+        if let Err(err) = written {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(format!("could not write spool segment: {}", err));
+        }
+
+        state.next_seq += 1;
+        state.bytes += size;
+        state.segments.push_back((seq, size));
+        self.report(&state);
+
+        Ok(())
+    }
+
+    /// The oldest segment, if any. Corrupt segments are discarded; segments that
+    /// can't be read right now are kept, and the error returned.
+    /// Reads files, so async code should call it with `spawn_blocking`.
+    pub fn oldest(&self) -> Result<Option<Segment>, String> {
+        loop {
+            let seq = match self.state.lock().unwrap().segments.front() {
+                Some((seq, _)) => *seq,
+                None => return Ok(None),
+            };
+
+            match self.read(seq) {
+                Ok(segment) => return Ok(Some(segment)),
+                Err(ReadError::Corrupt(err)) => {
+                    println!("Discarding spool segment {}: {}", seq, err);
+                    self.self_metrics.incr("agent.spool.corrupt", &[], 1.0);
+                    self.remove(seq);
+                }
+                Err(ReadError::Io(err)) => {
+                    return Err(format!("could not read spool segment {}: {}", seq, err))
+                }
+            };
+        }
+    }
+
+    /// Remove a segment once it has been delivered.
+    /// Deletes a file, so async code should call it with `spawn_blocking`.
+    pub fn remove(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(idx) = state.segments.iter().position(|(s, _)| *s == seq) {
+            let (_, size) = state.segments.remove(idx).unwrap();
+            let _ = std::fs::remove_file(self.path(seq));
+            state.bytes -= size;
+        }
+
+        self.report(&state);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().segments.is_empty()
+    }
+
+    fn read(&self, seq: u64) -> Result<Segment, ReadError> {
+        let buf = match std::fs::read(self.path(seq)) {
+            Ok(buf) => buf,
+            // Deleted behind our back, there's nothing left to keep.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ReadError::Corrupt(err.to_string()))
+            }
+            Err(err) => return Err(ReadError::Io(err.to_string())),
+        };
+
+        if buf.len() < HEADER_LEN || &buf[0..4] != MAGIC {
+            return Err(ReadError::Corrupt("invalid header".to_string()));
+        }
+
+        let kind = Kind::from_byte(buf[4])
+            .ok_or_else(|| ReadError::Corrupt("unknown kind".to_string()))?;
+        let len = u32::from_le_bytes([buf[5], buf[6], buf[7], buf[8]]) as usize;
+        let crc = u32::from_le_bytes([buf[9], buf[10], buf[11], buf[12]]);
+        let payload = &buf[HEADER_LEN..];
+
+        if payload.len() != len {
+            return Err(ReadError::Corrupt(format!(
+                "expected {} bytes, found {}",
+                len,
+                payload.len()
+            )));
+        }
+
+        if crc32fast::hash(payload) != crc {
+            return Err(ReadError::Corrupt("checksum mismatch".to_string()));
+        }
+
+        Ok(Segment {
+            seq,
+            kind,
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.seg", seq))
+    }
+
+    fn report(&self, state: &State) {
+        self.self_metrics
+            .gauge("agent.spool.bytes", &[], state.bytes as f64);
+        self.self_metrics
+            .gauge("agent.spool.segments", &[], state.segments.len() as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool(name: &str) -> (PathBuf, Spool) {
+        spool_of(name, 1 << 20, Arc::new(SelfMetrics::new()))
+    }
+
+    fn spool_of(name: &str, max_bytes: u64, self_metrics: Arc<SelfMetrics>) -> (PathBuf, Spool) {
+        let dir =
+            std::env::temp_dir().join(format!("metricscat-spool-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let spool = Spool::open(&dir, max_bytes, self_metrics).unwrap();
+
+        (dir, spool)
+    }
+
+    fn count(self_metrics: &SelfMetrics, name: &str) -> f64 {
+        self_metrics
+            .report(&std::collections::HashMap::new())
+            .into_iter()
+            .filter(|metric| metric.name == name)
+            .map(|metric| metric.value)
+            .sum()
+    }
+
+    fn payloads(spool: &Spool) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+
+        while let Some(segment) = spool.oldest().unwrap() {
+            payloads.push(segment.payload);
+            spool.remove(segment.seq);
+        }
+
+        payloads
+    }
+
+    #[test]
+    fn returns_segments_oldest_first() {
+        let (dir, spool) = spool("order");
+
+        spool.push(Kind::Logs, b"first").unwrap();
+        spool.push(Kind::Metrics, b"second").unwrap();
+        spool.push(Kind::Logs, b"third").unwrap();
+
+        assert_eq!(
+            payloads(&spool),
+            [b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+        assert!(spool.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_oldest_segments_when_full() {
+        let self_metrics = Arc::new(SelfMetrics::new());
+        // Room for two 10 byte payloads and their headers.
+        let (dir, spool) = spool_of("evict", 2 * (HEADER_LEN as u64 + 10), self_metrics.clone());
+
+        spool.push(Kind::Logs, b"0123456789").unwrap();
+        spool.push(Kind::Logs, b"1123456789").unwrap();
+        spool.push(Kind::Logs, b"2123456789").unwrap();
+
+        assert_eq!(count(&self_metrics, "agent.spool.evicted"), 1.0);
+        assert_eq!(
+            payloads(&spool),
+            [b"1123456789".to_vec(), b"2123456789".to_vec()]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_batches_larger_than_the_spool() {
+        let self_metrics = Arc::new(SelfMetrics::new());
+        let (dir, spool) = spool_of("oversized", HEADER_LEN as u64 + 10, self_metrics.clone());
+
+        spool.push(Kind::Logs, b"0123456789").unwrap();
+        assert!(spool.push(Kind::Logs, b"01234567890").is_err());
+
+        // Nothing was evicted to make room for it.
+        assert_eq!(count(&self_metrics, "agent.spool.oversized"), 1.0);
+        assert_eq!(count(&self_metrics, "agent.spool.evicted"), 0.0);
+        assert_eq!(payloads(&spool), [b"0123456789".to_vec()]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn picks_up_segments_after_a_restart() {
+        let (dir, spool) = spool("restart");
+
+        spool.push(Kind::Logs, b"first").unwrap();
+        spool.push(Kind::Metrics, b"second").unwrap();
+        drop(spool);
+
+        let spool = Spool::open(&dir, 1 << 20, Arc::new(SelfMetrics::new())).unwrap();
+        spool.push(Kind::Logs, b"third").unwrap();
+
+        assert_eq!(
+            payloads(&spool),
+            [b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discards_corrupt_segments() {
+        let (dir, spool) = spool("corrupt");
+
+        spool.push(Kind::Logs, b"first").unwrap();
+        spool.push(Kind::Metrics, b"second").unwrap();
+        std::fs::write(spool.path(0), b"MCS1 garbage").unwrap();
+
+        let segment = spool.oldest().unwrap().unwrap();
+        assert_eq!((segment.seq, segment.payload), (1, b"second".to_vec()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_segments_it_cannot_read_yet() {
+        let (dir, spool) = spool("unreadable");
+
+        spool.push(Kind::Logs, b"first").unwrap();
+        // Reading a directory fails with an IO error rather than bad contents.
+        std::fs::remove_file(spool.path(0)).unwrap();
+        std::fs::create_dir(spool.path(0)).unwrap();
+
+        assert!(spool.oldest().is_err());
+        assert!(!spool.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
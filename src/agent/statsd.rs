@@ -0,0 +1,368 @@
+// StatsD protocol parser.
+//
+// Supports the Etsy StatsD line format with DogStatsD extensions:
+//
+//   <name>:<value>[:<value>...]|<type>[|@<sample rate>][|#<tag>:<value>,...]
+//
+// Multiple metrics can be sent in one packet, separated by newlines.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::Metric;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// `c`, incremented by this amount.
+    Counter(f64),
+    /// `g`, set to this value.
+    Gauge(f64),
+    /// `g` with a leading sign, adjusts the current value.
+    GaugeDelta(f64),
+    /// `ms`, duration in milliseconds.
+    Timer(f64),
+    /// `h` (or `d`), a sampled value.
+    Histogram(f64),
+    /// `s`, a member of a set of unique values.
+    Set(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub value: Value,
+    pub sample_rate: f64,
+    pub tags: HashMap<String, String>,
+}
+
+/// Parse a UDP packet which may contain several newline-separated metrics.
+/// Every line produces either its samples or a description of what is wrong with it.
+pub fn parse_packet(packet: &str) -> Vec<Result<Vec<Sample>, String>> {
+    packet
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+/// Parse a single metric line. Multiple values can be packed into one line
+/// (`name:1:2:3|ms`), in which case one sample is returned per value.
+pub fn parse_line(line: &str) -> Result<Vec<Sample>, String> {
+    let mut sections = line.split('|');
+
+    let name_values = sections.next().unwrap_or("");
+    let kind = match sections.next() {
+        Some(kind) => kind,
+        None => return Err(format!("\"{}\": missing metric type", line)),
+    };
+
+    let mut parts = name_values.split(':');
+    let name = parts.next().unwrap_or("").trim();
+    let values: Vec<&str> = parts.collect();
+
+    if name.is_empty() {
+        return Err(format!("\"{}\": missing metric name", line));
+    }
+
+    if values.is_empty() || values.iter().any(|value| value.is_empty()) {
+        return Err(format!("\"{}\": missing metric value", line));
+    }
+
+    let mut sample_rate = 1.0;
+    let mut tags = HashMap::new();
+
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            sample_rate = match rate.parse::<f64>() {
+                Ok(rate) if rate > 0.0 && rate <= 1.0 => rate,
+                _ => return Err(format!("\"{}\": invalid sample rate \"{}\"", line, rate)),
+            };
+        } else if let Some(tag_list) = section.strip_prefix('#') {
+            for tag in tag_list.split(',').filter(|tag| !tag.is_empty()) {
+                let (tag_name, tag_value) = match tag.split_once(':') {
+                    Some((tag_name, tag_value)) => (tag_name, tag_value),
+                    None => (tag, ""),
+                };
+
+                if tag_name.is_empty() {
+                    return Err(format!("\"{}\": invalid tag \"{}\"", line, tag));
+                }
+
+                tags.insert(tag_name.to_string(), tag_value.to_string());
+            }
+        }
+        // Other DogStatsD extensions (container ID, timestamp) are ignored.
+    }
+
+    values
+        .iter()
+        .map(|value| {
+            Ok(Sample {
+                name: name.to_string(),
+                value: parse_value(line, kind, value)?,
+                sample_rate,
+                tags: tags.clone(),
+            })
+        })
+        .collect()
+}
+
+fn parse_value(line: &str, kind: &str, value: &str) -> Result<Value, String> {
+    let number = || {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(|| format!("\"{}\": invalid value \"{}\"", line, value))
+    };
+
+    match kind {
+        "c" => Ok(Value::Counter(number()?)),
+        "g" => {
+            if value.starts_with('+') || value.starts_with('-') {
+                Ok(Value::GaugeDelta(number()?))
+            } else {
+                Ok(Value::Gauge(number()?))
+            }
+        }
+        "ms" => Ok(Value::Timer(number()?)),
+        "h" | "d" => Ok(Value::Histogram(number()?)),
+        "s" => Ok(Value::Set(value.to_string())),
+        _ => Err(format!("\"{}\": unknown metric type \"{}\"", line, kind)),
+    }
+}
+
+/// Metric name and sorted tags, identifying one aggregated series.
+type Key = (String, BTreeMap<String, String>);
+
+/// Gauges not updated for this many flushes are forgotten, so short-lived
+/// series (pods, request ids) don't pile up. A later delta then starts from 0.
+const GAUGE_IDLE_FLUSHES: u32 = 10;
+
+/// Aggregates samples over a flush window, like statsd does:
+/// counters are summed, gauges keep their last value, timers and histograms
+/// are summarized into count/min/max/mean/percentiles and sets are counted.
+pub struct Aggregator {
+    percentiles: Vec<f64>,
+    counters: HashMap<Key, f64>,
+    /// Last value and how many flushes ago it was updated.
+    gauges: HashMap<Key, (f64, u32)>,
+    timers: HashMap<Key, (Vec<f64>, f64)>,
+    sets: HashMap<Key, HashSet<String>>,
+}
+
+impl Aggregator {
+    pub fn new(percentiles: &[f64]) -> Aggregator {
+        Aggregator {
+            percentiles: percentiles.to_vec(),
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            timers: HashMap::new(),
+            sets: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, sample: Sample) {
+        let key = (sample.name, sample.tags.into_iter().collect());
+
+        match sample.value {
+            Value::Counter(value) => {
+                *self.counters.entry(key).or_insert(0.0) += value / sample.sample_rate;
+            }
+            Value::Gauge(value) => {
+                self.gauges.insert(key, (value, 0));
+            }
+            Value::GaugeDelta(value) => {
+                let gauge = self.gauges.entry(key).or_insert((0.0, 0));
+                gauge.0 += value;
+                gauge.1 = 0;
+            }
+            Value::Timer(value) | Value::Histogram(value) => {
+                let (values, count) = self.timers.entry(key).or_insert((Vec::new(), 0.0));
+                values.push(value);
+                *count += 1.0 / sample.sample_rate;
+            }
+            Value::Set(member) => {
+                self.sets.entry(key).or_default().insert(member);
+            }
+        };
+    }
+
+    /// Summarize everything received since the last flush and reset the window.
+    /// Gauges keep reporting their last value, which later deltas apply to, until they go idle.
+    pub fn flush(&mut self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+
+        for ((name, tags), value) in self.counters.drain() {
+            metrics.push(metric(name, value, &tags));
+        }
+
+        for ((name, tags), (value, idle_flushes)) in self.gauges.iter_mut() {
+            metrics.push(metric(name.clone(), *value, tags));
+            *idle_flushes += 1;
+        }
+
+        self.gauges
+            .retain(|_, (_, idle_flushes)| *idle_flushes <= GAUGE_IDLE_FLUSHES);
+
+        for ((name, tags), (mut values, count)) in self.timers.drain() {
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let sum: f64 = values.iter().sum();
+
+            metrics.push(metric(format!("{}.count", name), count, &tags));
+            metrics.push(metric(format!("{}.min", name), values[0], &tags));
+            metrics.push(metric(
+                format!("{}.max", name),
+                values[values.len() - 1],
+                &tags,
+            ));
+            metrics.push(metric(
+                format!("{}.mean", name),
+                sum / values.len() as f64,
+                &tags,
+            ));
+
+            for percentile in &self.percentiles {
+                // Nearest-rank percentile.
+                let rank = (percentile / 100.0 * values.len() as f64).ceil() as usize;
+                let value = values[rank.clamp(1, values.len()) - 1];
+                let suffix = percentile.to_string().replace('.', "_");
+
+                metrics.push(metric(format!("{}.p{}", name, suffix), value, &tags));
+            }
+        }
+
+        for ((name, tags), members) in self.sets.drain() {
+            metrics.push(metric(name, members.len() as f64, &tags));
+        }
+
+        metrics
+    }
+}
+
+fn metric(name: String, value: f64, tags: &BTreeMap<String, String>) -> Metric {
+    Metric {
+        name,
+        value,
+        tags: tags.clone().into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(line: &str) -> Sample {
+        let mut samples = parse_line(line).unwrap();
+        assert_eq!(samples.len(), 1);
+        samples.remove(0)
+    }
+
+    #[test]
+    fn parses_each_metric_type() {
+        assert_eq!(sample("hits:2|c").value, Value::Counter(2.0));
+        assert_eq!(sample("temp:21.5|g").value, Value::Gauge(21.5));
+        assert_eq!(sample("took:320|ms").value, Value::Timer(320.0));
+        assert_eq!(sample("size:1024|h").value, Value::Histogram(1024.0));
+        assert_eq!(sample("size:1024|d").value, Value::Histogram(1024.0));
+        assert_eq!(
+            sample("users:alice|s").value,
+            Value::Set("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_signed_gauges_as_deltas() {
+        assert_eq!(sample("queue:+3|g").value, Value::GaugeDelta(3.0));
+        assert_eq!(sample("queue:-4.5|g").value, Value::GaugeDelta(-4.5));
+        // Only gauges have deltas.
+        assert_eq!(sample("hits:-1|c").value, Value::Counter(-1.0));
+    }
+
+    #[test]
+    fn parses_sample_rates() {
+        assert_eq!(sample("hits:1|c|@0.1").sample_rate, 0.1);
+        assert_eq!(sample("hits:1|c").sample_rate, 1.0);
+        assert!(parse_line("hits:1|c|@0").is_err());
+        assert!(parse_line("hits:1|c|@1.5").is_err());
+        assert!(parse_line("hits:1|c|@fast").is_err());
+    }
+
+    #[test]
+    fn parses_tags() {
+        let sample = sample("hits:1|c|@0.5|#env:prod,role:web,canary");
+
+        assert_eq!(sample.sample_rate, 0.5);
+        assert_eq!(sample.tags.len(), 3);
+        assert_eq!(sample.tags["env"], "prod");
+        assert_eq!(sample.tags["role"], "web");
+        assert_eq!(sample.tags["canary"], "");
+        assert!(parse_line("hits:1|c|#:prod").is_err());
+    }
+
+    #[test]
+    fn parses_packed_values() {
+        let samples = parse_line("took:1:2:3|ms|#env:prod").unwrap();
+
+        assert_eq!(
+            samples.iter().map(|x| x.value.clone()).collect::<Vec<_>>(),
+            vec![Value::Timer(1.0), Value::Timer(2.0), Value::Timer(3.0)]
+        );
+        assert!(samples.iter().all(|x| x.tags["env"] == "prod"));
+    }
+
+    #[test]
+    fn parses_multi_line_packets() {
+        let results = parse_packet("hits:1|c\n\ntemp:20|g\r\nbroken\nusers:bob|s\n");
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap()[0].name, "hits");
+        assert_eq!(results[1].as_ref().unwrap()[0].name, "temp");
+        assert!(results[2].is_err());
+        assert_eq!(results[3].as_ref().unwrap()[0].name, "users");
+    }
+
+    #[test]
+    fn reports_gauges_until_they_go_idle() {
+        let mut aggregator = Aggregator::new(&[]);
+
+        aggregator.add(sample("queue:5|g|#pod:a"));
+        assert_eq!(aggregator.flush()[0].value, 5.0);
+
+        // Deltas apply to the remembered value while the gauge is recent...
+        aggregator.add(sample("queue:+1|g|#pod:a"));
+        assert_eq!(aggregator.flush()[0].value, 6.0);
+
+        // ...which is reported every flush, updated or not...
+        for _ in 0..GAUGE_IDLE_FLUSHES {
+            let metrics = aggregator.flush();
+            assert_eq!(metrics.len(), 1);
+            assert_eq!(metrics[0].value, 6.0);
+        }
+
+        assert!(aggregator.gauges.is_empty());
+        assert!(aggregator.flush().is_empty());
+
+        // ...and start over once it was forgotten.
+        aggregator.add(sample("queue:+1|g|#pod:a"));
+        assert_eq!(aggregator.flush()[0].value, 1.0);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "hits",
+            "hits:1",
+            ":1|c",
+            "hits:|c",
+            "hits:1::2|c",
+            "hits:one|c",
+            "hits:NaN|c",
+            "hits:inf|g",
+            "hits:1|x",
+        ] {
+            assert!(parse_line(line).is_err(), "{} should be rejected", line);
+        }
+    }
+}
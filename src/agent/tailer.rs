@@ -0,0 +1,628 @@
+// Log file tailer.
+//
+// Follows a file as it's appended to and handles rotation:
+//
+// - rename and create (logrotate's default): the path points to a new inode.
+//   We finish reading the old file through the handle we still have open
+//   and then switch to the new one.
+// - copytruncate: the file shrinks below our offset, or its first bytes no longer
+//   match the saved fingerprint if it grew back past it. We start over from the beginning.
+//
+// If the agent was not running when the file was rotated, the rotated file
+// (`<path>.1`) is drained from the saved position before the new file is read.
+//
+// Tailers wake up on inotify events for their file where possible and poll otherwise.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Seek};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+use super::checkpoint::Checkpoints;
+use super::filter::Filter;
+use super::multiline::{Grouper, MultilineRules};
+use super::parse::LineParser;
+use super::queue::Queue;
+use super::redact::Redactor;
+use super::watcher::Watcher;
+use super::{process_logs, LogLine};
+
+/// Most lines read from disk in one go.
+const MAX_LINES_PER_READ: usize = 1_000;
+
+/// How often to check the file even without inotify events, in case we missed some.
+const INOTIFY_POLL_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+
+pub struct Tailer {
+    pub path: String,
+    pub tags: HashMap<String, String>,
+    pub multiline: MultilineRules,
+    pub poll_interval: tokio::time::Duration,
+    pub parser: Arc<LineParser>,
+    pub filter: Arc<Filter>,
+    pub redactor: Arc<Redactor>,
+    /// Use inotify instead of polling, if the filesystem supports it.
+    pub inotify: bool,
+    pub watcher: Arc<Watcher>,
+    pub log_lines: Arc<Queue<LogLine>>,
+    pub checkpoints: Arc<Checkpoints>,
+    /// Set when the file is gone for good; the tailer reads what's left and exits.
+    pub stop: Arc<AtomicBool>,
+    /// Notified along with `stop`, so the tailer doesn't sleep through it.
+    pub stopping: Arc<Notify>,
+}
+
+/// An open log file and how far we read it.
+struct OpenFile {
+    reader: std::io::BufReader<std::fs::File>,
+    dev: u64,
+    ino: u64,
+    // End of the last complete line we read.
+    offset: u64,
+    // Line still being written, without its newline yet.
+    partial: Vec<u8>,
+}
+
+impl OpenFile {
+    fn open(path: &str) -> std::io::Result<OpenFile> {
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+
+        Ok(OpenFile {
+            reader: std::io::BufReader::new(file),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            offset: 0,
+            partial: Vec::new(),
+        })
+    }
+
+    fn seek(&mut self, offset: u64) -> std::io::Result<()> {
+        self.reader.seek(std::io::SeekFrom::Start(offset))?;
+        self.offset = offset;
+        self.partial.clear();
+
+        Ok(())
+    }
+
+    /// Read complete lines, up to `max`, with their size in bytes. A line without
+    /// its newline yet is kept until the rest of it is written.
+    fn read_lines(&mut self, max: usize) -> std::io::Result<Vec<(String, u64)>> {
+        let mut lines = Vec::new();
+
+        while lines.len() < max {
+            let n = self.reader.read_until(b'\n', &mut self.partial)?;
+
+            if n == 0 || self.partial.last() != Some(&b'\n') {
+                break;
+            }
+
+            self.offset += self.partial.len() as u64;
+            lines.push((
+                String::from_utf8_lossy(&self.partial).to_string(),
+                self.partial.len() as u64,
+            ));
+            self.partial.clear();
+        }
+
+        Ok(lines)
+    }
+
+    /// The last line, if it was never finished with a newline.
+    fn take_partial(&mut self) -> Option<(String, u64)> {
+        if self.partial.is_empty() {
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(&self.partial).to_string();
+        let bytes = self.partial.len() as u64;
+
+        self.offset += bytes;
+        self.partial.clear();
+
+        Some((line, bytes))
+    }
+}
+
+impl Tailer {
+    pub async fn run(self) {
+        let mut grouper = Grouper::new(self.multiline.clone());
+        let mut current: Option<OpenFile> = None;
+
+        let wake = match self.inotify {
+            true => self.watcher.subscribe(&self.path),
+            false => None,
+        };
+
+        println!(
+            "Starting collection of logs from {} ({})",
+            self.path,
+            match wake {
+                Some(_) => "inotify",
+                None => "polling",
+            }
+        );
+
+        // Rotated while we were not running.
+        if let Some(rotated) = self.open_rotated() {
+            println!(
+                "Finishing rotated file {}.1 from offset {}",
+                self.path, rotated.offset
+            );
+
+            self.read(rotated, &mut grouper, true).await;
+        }
+
+        loop {
+            if current.is_none() {
+                current = match OpenFile::open(&self.path) {
+                    Ok(mut file) => {
+                        // The directory may have been deleted and created again meanwhile.
+                        if wake.is_some() {
+                            self.watcher.rewatch(&self.path);
+                        }
+
+                        let offset = self.checkpoints.resume(&self.path, file.reader.get_ref());
+
+                        match file.seek(offset) {
+                            Ok(()) => Some(file),
+                            Err(err) => {
+                                println!("Could not seek {} to {}: {}", self.path, offset, err);
+                                None
+                            }
+                        }
+                    }
+
+                    // Not created yet (or not anymore), we'll check again later.
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+
+                    Err(err) => {
+                        println!("Can't open file {}: {}", self.path, err);
+                        None
+                    }
+                };
+            }
+
+            // Checked before reading, so we get whatever was written before we were stopped.
+            let stop = self.stop.load(Ordering::Relaxed);
+
+            current = match current.take() {
+                // Checked before reading, so we don't read a file that was truncated
+                // and written to again from the middle.
+                Some(mut file) if self.truncated(&file) => {
+                    println!("{} was truncated", self.path);
+
+                    // Whatever we were grouping is not coming back.
+                    if let Some(event) = grouper.flush() {
+                        self.emit(&event).await;
+                    }
+
+                    match file.seek(0) {
+                        Ok(()) => Some(file),
+                        Err(_) => None,
+                    }
+                }
+                file => file,
+            };
+
+            if let Some(file) = current.take() {
+                let file = self.read(file, &mut grouper, stop).await;
+
+                current = match std::fs::metadata(&self.path) {
+                    // Renamed and replaced by a new file. Pick up anything written
+                    // to the old file since we last read it, then switch.
+                    Ok(metadata) if metadata.dev() != file.dev || metadata.ino() != file.ino => {
+                        println!("{} was rotated", self.path);
+                        self.read(file, &mut grouper, true).await;
+                        None
+                    }
+
+                    // Still the same file, or renamed and not replaced yet,
+                    // in which case we keep reading the old one.
+                    _ => Some(file),
+                };
+            }
+
+            if stop {
+                println!("Stopping collection of logs from {}", self.path);
+                self.checkpoints.remove(&self.path);
+
+                if let Some(wake) = &wake {
+                    self.watcher.unsubscribe(&self.path, wake);
+                }
+
+                return;
+            }
+
+            // Wake up in time to flush the pending event if nothing else arrives.
+            let flush_in = grouper.time_left().unwrap_or(tokio::time::Duration::MAX);
+
+            let sleep = async {
+                match &wake {
+                    Some(wake) => {
+                        let timeout = INOTIFY_POLL_INTERVAL.min(flush_in);
+                        let _ = tokio::time::timeout(timeout, wake.notified()).await;
+                    }
+                    None => tokio::time::sleep(self.poll_interval.min(flush_in)).await,
+                };
+            };
+
+            tokio::select! {
+                _ = sleep => (),
+                _ = self.stopping.notified() => (),
+            };
+        }
+    }
+
+    /// Read everything written to `file` so far and queue it for shipping.
+    /// With `flush`, we're done with the file: the last event is shipped even if it
+    /// could still get more lines, along with a last line missing its newline.
+    async fn read(&self, mut file: OpenFile, grouper: &mut Grouper, flush: bool) -> OpenFile {
+        loop {
+            let (returned, lines) = tokio::task::spawn_blocking(move || {
+                let lines = file.read_lines(MAX_LINES_PER_READ);
+                (file, lines)
+            })
+            .await
+            .unwrap();
+
+            file = returned;
+
+            let lines = match lines {
+                Ok(lines) => lines,
+                Err(err) => {
+                    println!("Error reading file {}: {}", self.path, err);
+                    break;
+                }
+            };
+
+            if lines.is_empty() {
+                break;
+            }
+
+            for (line, bytes) in lines {
+                if let Some(event) = grouper.push(&line, bytes) {
+                    self.emit(&event).await;
+                }
+            }
+        }
+
+        if flush {
+            if let Some((line, bytes)) = file.take_partial() {
+                if let Some(event) = grouper.push(&line, bytes) {
+                    self.emit(&event).await;
+                }
+            }
+        }
+
+        // Reached end of file. The last event is complete once no more lines
+        // were added to it for a while.
+        let event = match flush {
+            true => grouper.flush(),
+            false => grouper.flush_expired(),
+        };
+
+        if let Some(event) = event {
+            self.emit(&event).await;
+        }
+
+        // Everything up to the event still being grouped is on its way to the server.
+        self.checkpoints.update(
+            &self.path,
+            file.reader.get_ref(),
+            file.offset - grouper.pending_bytes(),
+        );
+
+        file
+    }
+
+    async fn emit(&self, event: &str) {
+        // Maybe publish logs if we have enough of them.
+        process_logs(
+            &self.log_lines,
+            event,
+            &self.parser,
+            &self.filter,
+            &self.redactor,
+            &self.tags,
+        )
+        .await;
+    }
+
+    /// Whether `file` was truncated since we last read it: it's shorter than where
+    /// we got to, or it doesn't start like it did when we saved our position.
+    fn truncated(&self, file: &OpenFile) -> bool {
+        let metadata = match file.reader.get_ref().metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+
+        if metadata.len() < file.offset {
+            return true;
+        }
+
+        match self.checkpoints.get(&self.path) {
+            Some(checkpoint) if checkpoint.dev == file.dev && checkpoint.ino == file.ino => {
+                !checkpoint.matches(file.reader.get_ref())
+            }
+            _ => false,
+        }
+    }
+
+    /// The rotated file (`<path>.1`), positioned where we stopped reading it,
+    /// if the saved position belongs to it rather than to the current file.
+    fn open_rotated(&self) -> Option<OpenFile> {
+        let checkpoint = self.checkpoints.get(&self.path)?;
+        let current = std::fs::metadata(&self.path).ok();
+
+        if let Some(metadata) = current {
+            if metadata.dev() == checkpoint.dev && metadata.ino() == checkpoint.ino {
+                return None;
+            }
+        }
+
+        let mut rotated = OpenFile::open(&format!("{}.1", self.path)).ok()?;
+
+        if !checkpoint.matches(rotated.reader.get_ref()) {
+            return None;
+        }
+
+        rotated.seek(checkpoint.offset).ok()?;
+
+        Some(rotated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::parse::{Format, LevelDetector};
+    use crate::agent::queue::Policy;
+    use crate::agent::self_metrics::SelfMetrics;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    struct Running {
+        log_lines: Arc<Queue<LogLine>>,
+        stop: Arc<AtomicBool>,
+        stopping: Arc<Notify>,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    impl Running {
+        /// The next `count` lines shipped, without their newlines.
+        async fn lines(&self, count: usize) -> Vec<String> {
+            let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(5);
+            let mut lines = Vec::new();
+
+            while lines.len() < count && tokio::time::Instant::now() < deadline {
+                let batch = self
+                    .log_lines
+                    .pop_batch(
+                        count - lines.len(),
+                        usize::MAX,
+                        tokio::time::Duration::from_millis(50),
+                    )
+                    .await;
+
+                lines.extend(
+                    batch
+                        .into_iter()
+                        .map(|line| line.line.trim_end().to_string()),
+                );
+            }
+
+            lines
+        }
+
+        async fn stop(self) {
+            self.stop.store(true, Ordering::Relaxed);
+            self.stopping.notify_one();
+            self.task.await.unwrap();
+        }
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("metricscat-tailer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn append(path: &Path, contents: &str) {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+    }
+
+    /// A polling tailer for `path`, shipping every line as an event of its own.
+    fn start(path: &Path, checkpoints: Arc<Checkpoints>, poll_interval_ms: u64) -> Running {
+        start_with(path, checkpoints, poll_interval_ms, false)
+    }
+
+    fn start_with(
+        path: &Path,
+        checkpoints: Arc<Checkpoints>,
+        poll_interval_ms: u64,
+        inotify: bool,
+    ) -> Running {
+        let self_metrics = Arc::new(SelfMetrics::new());
+        let log_lines = Arc::new(Queue::new("logs", 100, Policy::Block, self_metrics.clone()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(Notify::new());
+        let path = path.to_str().unwrap().to_string();
+
+        let tailer = Tailer {
+            tags: HashMap::new(),
+            multiline: MultilineRules {
+                start: None,
+                negate_start: false,
+                continuation: None,
+                negate_continuation: false,
+                max_lines: 500,
+                max_bytes: 1 << 20,
+                flush_timeout: tokio::time::Duration::ZERO,
+            },
+            poll_interval: tokio::time::Duration::from_millis(poll_interval_ms),
+            parser: Arc::new(LineParser {
+                format: Format::Text,
+                timestamp: None,
+                levels: LevelDetector::new(&HashMap::new()).unwrap(),
+            }),
+            filter: Arc::new(Filter::new(Default::default(), &path, self_metrics.clone())),
+            redactor: Arc::new(Redactor::new(Vec::new(), self_metrics)),
+            inotify,
+            watcher: Arc::new(Watcher::new()),
+            log_lines: log_lines.clone(),
+            checkpoints,
+            stop: stop.clone(),
+            stopping: stopping.clone(),
+            path,
+        };
+
+        Running {
+            log_lines,
+            stop,
+            stopping,
+            task: tokio::task::spawn(tailer.run()),
+        }
+    }
+
+    #[tokio::test]
+    async fn wakes_up_on_inotify_events() {
+        let dir = dir("inotify");
+        let log = dir.join("app.log");
+        append(&log, "first\n");
+
+        // Without the inotify event, the next read would be 10 seconds away.
+        let tailer = start_with(
+            &log,
+            Arc::new(Checkpoints::load(None).unwrap()),
+            60_000,
+            true,
+        );
+        assert_eq!(tailer.lines(1).await, ["first"]);
+
+        let written = tokio::time::Instant::now();
+        append(&log, "second\n");
+
+        assert_eq!(tailer.lines(1).await, ["second"]);
+        assert!(written.elapsed() < tokio::time::Duration::from_secs(2));
+
+        tailer.stop().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn polls_without_inotify() {
+        let dir = dir("polling");
+        let log = dir.join("app.log");
+        append(&log, "first\n");
+
+        let tailer = start(&log, Arc::new(Checkpoints::load(None).unwrap()), 60_000);
+        assert_eq!(tailer.lines(1).await, ["first"]);
+
+        // Not noticed until the next poll.
+        append(&log, "second\n");
+
+        let batch = tailer
+            .log_lines
+            .pop_batch(1, usize::MAX, tokio::time::Duration::from_millis(500))
+            .await;
+        assert!(batch.is_empty());
+
+        tailer.stop().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn follows_renamed_and_recreated_files() {
+        let dir = dir("renamed");
+        let log = dir.join("app.log");
+        append(&log, "first\n");
+
+        let tailer = start(&log, Arc::new(Checkpoints::load(None).unwrap()), 20);
+        assert_eq!(tailer.lines(1).await, ["first"]);
+
+        // Still written to after the rename, and the last line never got its newline.
+        std::fs::rename(&log, dir.join("app.log.1")).unwrap();
+        append(&dir.join("app.log.1"), "second\nthird");
+        append(&log, "fourth\n");
+
+        assert_eq!(tailer.lines(3).await, ["second", "third", "fourth"]);
+
+        tailer.stop().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finishes_files_rotated_while_not_running() {
+        let dir = dir("rotated");
+        let log = dir.join("app.log");
+        append(&log, "first\n");
+
+        // Where the last run stopped.
+        let checkpoints = Arc::new(Checkpoints::load(None).unwrap());
+        checkpoints.update(
+            log.to_str().unwrap(),
+            &std::fs::File::open(&log).unwrap(),
+            6,
+        );
+
+        append(&log, "second\n");
+        std::fs::rename(&log, dir.join("app.log.1")).unwrap();
+        append(&log, "third\n");
+
+        let tailer = start(&log, checkpoints, 20);
+        assert_eq!(tailer.lines(2).await, ["second", "third"]);
+
+        tailer.stop().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_truncated() {
+        let dir = dir("truncated");
+        let log = dir.join("app.log");
+        append(&log, "first line\nsecond line\n");
+
+        let tailer = start(&log, Arc::new(Checkpoints::load(None).unwrap()), 20);
+        assert_eq!(tailer.lines(2).await, ["first line", "second line"]);
+
+        // Shorter than where we got to.
+        std::fs::write(&log, "new\n").unwrap();
+        assert_eq!(tailer.lines(1).await, ["new"]);
+
+        tailer.stop().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_truncated_and_written_past_the_offset() {
+        let dir = dir("regrown");
+        let log = dir.join("app.log");
+        append(&log, "first line\nsecond line\n");
+
+        // Slow enough for the file to grow back before it's read again.
+        let tailer = start(&log, Arc::new(Checkpoints::load(None).unwrap()), 500);
+        assert_eq!(tailer.lines(2).await, ["first line", "second line"]);
+
+        std::fs::write(&log, "new 1\nnew 2\nnew 3\nnew 4\nnew 5\n").unwrap();
+        assert_eq!(
+            tailer.lines(5).await,
+            ["new 1", "new 2", "new 3", "new 4", "new 5"]
+        );
+
+        tailer.stop().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,503 @@
+// Delivery of metrics and logs to the server.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use super::config::TlsConfig;
+use super::dead_letter::DeadLetter;
+use super::retry::{self, Failure, RetryPolicy};
+use super::spool::{Kind, Spool};
+use super::{LogLine, Metric};
+
+/// How request bodies are compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Content-Encoding of compressed bodies.
+    fn encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    fn compress(&self, body: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(body),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::stream::encode_all(&body[..], 0),
+        }
+    }
+}
+
+pub struct Uploader {
+    api: String,
+    // Shared by all requests, so connections are kept alive and reused.
+    client: reqwest::Client,
+    compression: Compression,
+    retry: RetryPolicy,
+    spool: Option<Arc<Spool>>,
+    dead_letter: DeadLetter,
+}
+
+impl Uploader {
+    pub fn new(
+        api: &str,
+        compression: Compression,
+        retry: RetryPolicy,
+        api_key: Option<&str>,
+        tls: Option<&TlsConfig>,
+        spool: Option<Spool>,
+        dead_letter: DeadLetter,
+    ) -> Result<Uploader, String> {
+        let client = client(api_key, tls)?;
+
+        Ok(Uploader {
+            api: api.to_string(),
+            client,
+            compression,
+            retry,
+            spool: spool.map(Arc::new),
+            dead_letter,
+        })
+    }
+
+    pub async fn send_metrics(&self, metrics: &[Metric]) {
+        self.deliver(Kind::Metrics, json!(metrics).to_string().into_bytes())
+            .await;
+    }
+
+    pub async fn send_logs(&self, logs: &[LogLine]) {
+        self.deliver(Kind::Logs, json!(logs).to_string().into_bytes())
+            .await;
+    }
+
+    /// Send a batch to the server, spooling it to disk if the server can't take it right now.
+    /// Batches the server won't ever take go to the dead letter directory.
+    async fn deliver(&self, kind: Kind, body: Vec<u8>) {
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => {
+                if let Err(failure) = self.post(kind, &body).await {
+                    self.dead_letter
+                        .store(name(kind), body, &failure.to_string())
+                        .await;
+                }
+                return;
+            }
+        };
+
+        // Older batches are still waiting, queue behind them to keep the order.
+        if !spool.is_empty() {
+            self.spool(spool, kind, body).await;
+            return;
+        }
+
+        match self.post(kind, &body).await {
+            Ok(()) => (),
+            Err(Failure::Permanent(error)) => {
+                self.dead_letter.store(name(kind), body, &error).await
+            }
+            Err(failure) => {
+                println!("Spooling {} to disk: {}", name(kind), failure);
+                self.spool(spool, kind, body).await;
+            }
+        };
+    }
+
+    /// Spool a batch on the blocking thread pool, as writing it syncs a file.
+    /// Batches that can't be spooled go to the dead letter directory.
+    async fn spool(&self, spool: &Arc<Spool>, kind: Kind, body: Vec<u8>) {
+        let spool = spool.clone();
+        let (body, pushed) = tokio::task::spawn_blocking(move || {
+            let pushed = spool.push(kind, &body);
+            (body, pushed)
+        })
+        .await
+        .unwrap();
+
+        if let Err(err) = pushed {
+            self.dead_letter.store(name(kind), body, &err).await;
+        }
+    }
+
+    /// Deliver spooled batches, oldest first, until the spool is empty
+    /// or the server stops accepting them. The spool is read on the blocking thread pool.
+    pub async fn replay(&self) {
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => return,
+        };
+
+        loop {
+            let oldest = spool.clone();
+            let segment = match tokio::task::spawn_blocking(move || oldest.oldest())
+                .await
+                .unwrap()
+            {
+                Ok(Some(segment)) => segment,
+                Ok(None) => break,
+                // Try again on the next replay.
+                Err(err) => {
+                    println!("{}", err);
+                    break;
+                }
+            };
+
+            match self.post(segment.kind, &segment.payload).await {
+                Ok(()) => (),
+                // Don't let a batch the server rejects hold up the ones behind it.
+                Err(Failure::Permanent(error)) => {
+                    self.dead_letter
+                        .store(name(segment.kind), segment.payload, &error)
+                        .await;
+                }
+                Err(_) => break,
+            };
+
+            let remove = spool.clone();
+            tokio::task::spawn_blocking(move || remove.remove(segment.seq))
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Post a batch, retrying transient failures according to the retry policy.
+    async fn post(&self, kind: Kind, body: &[u8]) -> Result<(), Failure> {
+        // Spooled batches are kept uncompressed, so they don't depend on the configuration.
+        let body = self
+            .compression
+            .compress(body.to_vec())
+            .map_err(|err| Failure::Permanent(format!("could not compress: {}", err)))?;
+
+        let url = format!("{}/{}", self.api, name(kind));
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let failure = match self.try_post(&url, body.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+
+            let retry_after = match &failure {
+                Failure::Transient { retry_after, .. } if attempt < self.retry.max_attempts => {
+                    *retry_after
+                }
+                Failure::Transient { error, .. } => {
+                    return Err(Failure::Transient {
+                        error: format!("failed after {} attempts: {}", attempt, error),
+                        retry_after: None,
+                    })
+                }
+                Failure::Permanent(_) => return Err(failure),
+            };
+
+            tokio::time::sleep(self.retry.backoff(attempt, retry_after)).await;
+        }
+    }
+
+    async fn try_post(&self, url: &str, body: Vec<u8>) -> Result<(), Failure> {
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+
+        if let Some(encoding) = self.compression.encoding() {
+            request = request.header(reqwest::header::CONTENT_ENCODING, encoding);
+        }
+
+        // Connection errors, timeouts and the like are all worth retrying.
+        let response = request.send().await.map_err(|err| Failure::Transient {
+            error: err.to_string(),
+            retry_after: None,
+        })?;
+
+        if response.status().is_success() {
+            return Ok(());
+        }
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        Err(retry::classify(
+            status,
+            retry_after.as_deref(),
+            response.text().await.unwrap_or_default(),
+        ))
+    }
+}
+
+/// Every request carries the API key, if any. With TLS configured, the server
+/// is verified against the CA and the agent presents its client certificate.
+fn client(api_key: Option<&str>, tls: Option<&TlsConfig>) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .pool_idle_timeout(tokio::time::Duration::from_secs(90))
+        .tcp_keepalive(tokio::time::Duration::from_secs(60))
+        .timeout(tokio::time::Duration::from_secs(30));
+
+    if let Some(api_key) = api_key {
+        let mut value = reqwest::header::HeaderValue::from_str(api_key)
+            .map_err(|_| "api_key: must only contain visible ASCII characters".to_string())?;
+        value.set_sensitive(true);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("X-Api-Key", value);
+        builder = builder.default_headers(headers);
+    }
+
+    if let Some(tls) = tls {
+        // Client certificates in PEM are only supported by rustls,
+        // not by the native TLS backend reqwest picks by default.
+        builder = builder.use_rustls_tls().https_only(true);
+
+        if let Some(path) = &tls.ca_cert {
+            let ca =
+                reqwest::Certificate::from_pem(&read("tls.ca_cert", path)?).map_err(|err| {
+                    format!("tls.ca_cert: {} is not a valid certificate: {}", path, err)
+                })?;
+
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(ca);
+        }
+
+        if let (Some(cert), Some(key)) = (&tls.client_cert, &tls.client_key) {
+            // The certificate chain and its key, in one PEM.
+            let mut pem = read("tls.client_cert", cert)?;
+            pem.push(b'\n');
+            pem.extend(read("tls.client_key", key)?);
+
+            let identity = reqwest::Identity::from_pem(&pem).map_err(|err| {
+                format!(
+                    "tls: {} and {} are not a valid certificate and key: {}",
+                    cert, key, err
+                )
+            })?;
+
+            builder = builder.identity(identity);
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|err| format!("could not create HTTP client: {}", err))
+}
+
+fn read(field: &str, path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("{}: could not read {}: {}", field, path, err))
+}
+
+/// Retry spooled batches in the background.
+pub async fn replay_forever(uploader: Arc<Uploader>) {
+    let duration = tokio::time::Duration::from_millis(5_000);
+
+    loop {
+        tokio::time::sleep(duration).await;
+        uploader.replay().await;
+    }
+}
+
+fn name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Metrics => "metrics",
+        Kind::Logs => "logs",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::super::self_metrics::SelfMetrics;
+
+    /// A server answering every request with `status`, keeping the bodies it got.
+    async fn server(status: u16) -> (String, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}/api", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+
+                // Read the headers, then as much body as they announce.
+                let body_len = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+
+                    if let Some(end) = find(&request, b"\r\n\r\n") {
+                        let headers = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let len = headers
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map(|len| len.trim().parse::<usize>().unwrap())
+                            .unwrap_or(0);
+                        request.drain(..end + 4);
+                        break len;
+                    }
+                };
+
+                while request.len() < body_len {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                received.lock().unwrap().push(request);
+
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (api, bodies)
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    fn uploader(api: &str, name: &str) -> (PathBuf, Uploader) {
+        let dir =
+            std::env::temp_dir().join(format!("metricscat-upload-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let self_metrics = Arc::new(SelfMetrics::new());
+        let spool = Spool::open(&dir.join("spool"), 1 << 20, self_metrics.clone()).unwrap();
+        let dead_letter = DeadLetter::new(
+            Some(dir.join("dead_letter").to_str().unwrap()),
+            self_metrics,
+        )
+        .unwrap();
+        let retry = RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+
+        let uploader = Uploader::new(
+            api,
+            Compression::None,
+            retry,
+            None,
+            None,
+            Some(spool),
+            dead_letter,
+        )
+        .unwrap();
+
+        (dir, uploader)
+    }
+
+    fn dead_lettered(dir: &std::path::Path) -> usize {
+        std::fs::read_dir(dir.join("dead_letter")).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn retries_connection_errors() {
+        // Nothing listens on the port once the listener is gone.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}/api", listener.local_addr().unwrap());
+        drop(listener);
+
+        let (dir, uploader) = uploader(&api, "refused");
+
+        assert!(matches!(
+            uploader.post(Kind::Logs, b"[]").await,
+            Err(Failure::Transient { .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spools_batches_rejected_for_auth() {
+        let (api, bodies) = server(401).await;
+        let (dir, uploader) = uploader(&api, "auth");
+        let spool = uploader.spool.clone().unwrap();
+
+        uploader.deliver(Kind::Logs, b"[1]".to_vec()).await;
+        uploader.replay().await;
+
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+        assert_eq!(spool.oldest().unwrap().unwrap().payload, b"[1]".to_vec());
+        assert_eq!(dead_lettered(&dir), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_letters_batches_rejected_for_good() {
+        let (api, _) = server(422).await;
+        let (dir, uploader) = uploader(&api, "rejected");
+
+        uploader.deliver(Kind::Logs, b"[1]".to_vec()).await;
+
+        assert!(uploader.spool.as_ref().unwrap().is_empty());
+        assert_eq!(dead_lettered(&dir), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_spooled_batches_in_order() {
+        let (api, bodies) = server(200).await;
+        let (dir, uploader) = uploader(&api, "replay");
+        let spool = uploader.spool.clone().unwrap();
+
+        spool.push(Kind::Logs, b"[1]").unwrap();
+        spool.push(Kind::Metrics, b"[2]").unwrap();
+        // Queues behind the spooled batches rather than overtaking them.
+        uploader.deliver(Kind::Logs, b"[3]".to_vec()).await;
+        assert!(bodies.lock().unwrap().is_empty());
+
+        uploader.replay().await;
+
+        assert_eq!(
+            *bodies.lock().unwrap(),
+            [b"[1]".to_vec(), b"[2]".to_vec(), b"[3]".to_vec()]
+        );
+        assert!(spool.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
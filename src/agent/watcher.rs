@@ -0,0 +1,333 @@
+// File change notifications with Linux inotify.
+//
+// We watch the directories containing the tailed files rather than the files
+// themselves, so we also hear about files being created, renamed and deleted,
+// which is what happens when logs are rotated.
+//
+// Network and userspace filesystems don't deliver inotify events for changes
+// made elsewhere, so tailers on them keep polling.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_std::stream::StreamExt;
+use inotify::{Inotify, WatchDescriptor, WatchMask, Watches};
+use tokio::sync::Notify;
+
+struct Directory {
+    // None once the directory was deleted, until it is watched again.
+    wd: Option<WatchDescriptor>,
+    // Tailers waiting for changes to each file in the directory.
+    files: HashMap<OsString, Vec<Arc<Notify>>>,
+}
+
+struct Inner {
+    watches: Watches,
+    directories: HashMap<PathBuf, Directory>,
+}
+
+pub struct Watcher {
+    // None if inotify is not available, everyone polls then.
+    inner: Option<Arc<Mutex<Inner>>>,
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        let stream = Inotify::init().and_then(|inotify| inotify.into_event_stream(vec![0u8; 4096]));
+
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("inotify is not available, polling log files: {}", err);
+                return Watcher { inner: None };
+            }
+        };
+
+        let inner = Arc::new(Mutex::new(Inner {
+            watches: stream.watches(),
+            directories: HashMap::new(),
+        }));
+
+        let s_inner = inner.clone();
+        tokio::task::spawn(async move {
+            while let Some(event) = stream.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        println!("Error reading inotify events: {}", err);
+                        continue;
+                    }
+                };
+
+                let mut inner = s_inner.lock().unwrap();
+
+                for directory in inner.directories.values_mut() {
+                    let ours = directory.wd.as_ref() == Some(&event.wd);
+                    let gone = ours && event.mask.contains(inotify::EventMask::IGNORED);
+
+                    // The watch died with the directory, a new one is needed if it comes back.
+                    if gone {
+                        directory.wd = None;
+                    }
+
+                    // Lost events or the directory itself is gone, wake everyone up to check.
+                    let everyone = event.mask.contains(inotify::EventMask::Q_OVERFLOW) || gone;
+
+                    for (name, subscribers) in &directory.files {
+                        if everyone || (ours && event.name.as_ref() == Some(name)) {
+                            for subscriber in subscribers {
+                                subscriber.notify_one();
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Watcher { inner: Some(inner) }
+    }
+
+    /// Get notified when `path` changes. Returns None if changes
+    /// can't be watched and the caller should poll instead.
+    pub fn subscribe(&self, path: &str) -> Option<Arc<Notify>> {
+        let inner = self.inner.as_ref()?;
+        let (dir, name) = split(path)?;
+
+        if !supports_inotify(&dir) {
+            return None;
+        }
+
+        let mut inner = inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        match inner.directories.get_mut(&dir) {
+            Some(directory) if directory.wd.is_some() => (),
+            // Deleted and created again since it was first watched.
+            Some(directory) => {
+                directory.wd = Some(
+                    watch(&mut inner.watches, &dir)
+                        .map_err(|err| println!("Could not watch {}: {}", dir.display(), err))
+                        .ok()?,
+                );
+            }
+            None => {
+                let wd = watch(&mut inner.watches, &dir)
+                    .map_err(|err| println!("Could not watch {}: {}", dir.display(), err))
+                    .ok()?;
+
+                inner.directories.insert(
+                    dir.clone(),
+                    Directory {
+                        wd: Some(wd),
+                        files: HashMap::new(),
+                    },
+                );
+            }
+        };
+
+        let notify = Arc::new(Notify::new());
+
+        inner
+            .directories
+            .get_mut(&dir)
+            .unwrap()
+            .files
+            .entry(name)
+            .or_default()
+            .push(notify.clone());
+
+        Some(notify)
+    }
+
+    /// Watch the directory of `path` again if it was deleted since `subscribe`, e.g. once
+    /// the file is back. Does nothing if it's still watched or can't be watched yet.
+    pub fn rewatch(&self, path: &str) {
+        let (inner, (dir, _)) = match (self.inner.as_ref(), split(path)) {
+            (Some(inner), Some(split)) => (inner, split),
+            _ => return,
+        };
+
+        let mut inner = inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if let Some(directory) = inner.directories.get_mut(&dir) {
+            if directory.wd.is_none() {
+                directory.wd = watch(&mut inner.watches, &dir).ok();
+            }
+        }
+    }
+
+    /// Stop notifications for `path`, and stop watching its directory if nobody else is interested.
+    pub fn unsubscribe(&self, path: &str, notify: &Arc<Notify>) {
+        let (inner, (dir, name)) = match (self.inner.as_ref(), split(path)) {
+            (Some(inner), Some(split)) => (inner, split),
+            _ => return,
+        };
+
+        let mut inner = inner.lock().unwrap();
+
+        let directory = match inner.directories.get_mut(&dir) {
+            Some(directory) => directory,
+            None => return,
+        };
+
+        if let Some(subscribers) = directory.files.get_mut(&name) {
+            subscribers.retain(|subscriber| !Arc::ptr_eq(subscriber, notify));
+
+            if subscribers.is_empty() {
+                directory.files.remove(&name);
+            }
+        }
+
+        if directory.files.is_empty() {
+            let directory = inner.directories.remove(&dir).unwrap();
+
+            if let Some(wd) = directory.wd {
+                // Fails if the directory is already gone, which is fine.
+                let _ = inner.watches.remove(wd);
+            }
+        }
+    }
+}
+
+fn watch(watches: &mut Watches, dir: &Path) -> std::io::Result<WatchDescriptor> {
+    watches.add(
+        dir,
+        WatchMask::MODIFY
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO,
+    )
+}
+
+fn split(path: &str) -> Option<(PathBuf, OsString)> {
+    let path = Path::new(path);
+    Some((
+        path.parent()?.to_path_buf(),
+        path.file_name()?.to_os_string(),
+    ))
+}
+
+/// Does the filesystem `dir` is on deliver inotify events for all changes?
+fn supports_inotify(dir: &Path) -> bool {
+    let path = match std::ffi::CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+
+    delivers_inotify_events(stat.f_type as i64)
+}
+
+/// Whether filesystems of type `f_type`, as reported by statfs(2), deliver inotify
+/// events for changes made by other hosts or processes outside the kernel.
+fn delivers_inotify_events(f_type: i64) -> bool {
+    // From statfs(2).
+    const NFS_SUPER_MAGIC: i64 = 0x6969;
+    const SMB_SUPER_MAGIC: i64 = 0x517b;
+    const CIFS_MAGIC_NUMBER: i64 = 0xff53_4d42;
+    const SMB2_MAGIC_NUMBER: i64 = 0xfe53_4d42;
+    const FUSE_SUPER_MAGIC: i64 = 0x6573_5546;
+    const V9FS_MAGIC: i64 = 0x0102_1997;
+
+    !matches!(
+        f_type,
+        NFS_SUPER_MAGIC
+            | SMB_SUPER_MAGIC
+            | CIFS_MAGIC_NUMBER
+            | SMB2_MAGIC_NUMBER
+            | FUSE_SUPER_MAGIC
+            | V9FS_MAGIC
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "metricscat-watcher-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[tokio::test]
+    async fn wakes_subscribers_when_their_file_changes() {
+        let dir = dir("wake");
+        let log = dir.join("app.log");
+        let path = log.to_str().unwrap();
+        std::fs::write(&log, "").unwrap();
+
+        let watcher = Watcher::new();
+        let wake = watcher.subscribe(path).expect("inotify on the temp dir");
+
+        std::fs::write(&log, "first\n").unwrap();
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), wake.notified())
+            .await
+            .expect("woken by the write");
+
+        // The write may have sent a couple of events, let them all in.
+        while tokio::time::timeout(tokio::time::Duration::from_millis(100), wake.notified())
+            .await
+            .is_ok()
+        {}
+
+        // Not woken by changes to other files in the directory.
+        std::fs::write(dir.join("other.log"), "").unwrap();
+
+        assert!(
+            tokio::time::timeout(tokio::time::Duration::from_millis(200), wake.notified())
+                .await
+                .is_err()
+        );
+
+        watcher.unsubscribe(path, &wake);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn polls_files_it_cannot_watch() {
+        let watcher = Watcher::new();
+
+        // statfs fails on a directory that doesn't exist.
+        assert!(watcher
+            .subscribe("/nonexistent/metricscat/app.log")
+            .is_none());
+    }
+
+    #[test]
+    fn polls_network_and_userspace_filesystems() {
+        // ext4, xfs, btrfs and tmpfs.
+        for f_type in [0xef53, 0x5846_5342, 0x9123_683e, 0x0102_1994] {
+            assert!(delivers_inotify_events(f_type), "{:#x}", f_type);
+        }
+
+        // NFS, SMB, CIFS, SMB2, FUSE and 9p.
+        for f_type in [
+            0x6969,
+            0x517b,
+            0xff53_4d42,
+            0xfe53_4d42,
+            0x6573_5546,
+            0x0102_1997,
+        ] {
+            assert!(!delivers_inotify_events(f_type), "{:#x}", f_type);
+        }
+    }
+}
@@ -15,7 +15,7 @@ mod server;
 #[tokio::main]
 async fn main() {
     let args: Vec<_> = std::env::args().collect();
-    let usage = "Usage: metricscat <server|agent>";
+    let usage = "Usage: metricscat <server|agent [config.toml]|api-key ...|user ...|org ...>";
 
     if args.len() < 2 {
         println!("{}", usage);
@@ -26,19 +26,14 @@ async fn main() {
 
     match role.as_ref() {
         "server" => {
-            let db = sqlx::postgres::PgPoolOptions::new()
-                .max_connections(16)
-                .connect(
-                    &std::env::var("METRICSCAT_DATABASE_URL")
-                        .unwrap_or("postgres:///metrics".to_string()),
-                )
-                .await
-                .unwrap();
-            let cors = rocket_cors::CorsOptions {
-                ..Default::default()
-            }
-            .to_cors()
-            .unwrap();
+            let db = connect().await;
+            let cors = match server::cors::cors() {
+                Ok(cors) => cors,
+                Err(err) => {
+                    println!("{}", err);
+                    std::process::exit(1);
+                }
+            };
 
             match rocket::build()
                 .mount(
@@ -50,6 +45,17 @@ async fn main() {
                         server::api_logs_post,
                         server::api_logs_get,
                         server::api_logs_search_get,
+                        server::auth::api_sessions_post,
+                        server::auth::api_sessions_delete,
+                        server::auth::api_me_get,
+                        server::auth::api_users_get,
+                        server::auth::api_users_post,
+                        server::auth::api_users_put,
+                        server::auth::api_users_delete,
+                        server::api_key::api_keys_get,
+                        server::api_key::api_keys_post,
+                        server::api_key::api_keys_delete,
+                        server::org::api_usage_get,
                     ],
                 )
                 .manage(db)
@@ -61,11 +67,57 @@ async fn main() {
                 Err(err) => panic!("Rocket error: {}", err),
             };
         }
+        "api-key" => {
+            let db = connect().await;
+
+            if let Err(err) = server::api_key::command(&db, &args[2..]).await {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        "user" => {
+            let db = connect().await;
+
+            if let Err(err) = server::auth::command(&db, &args[2..]).await {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        "org" => {
+            let db = connect().await;
+
+            if let Err(err) = server::org::command(&db, &args[2..]).await {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
         "agent" => {
-            agent::launch().await;
+            let config = match args.get(2) {
+                Some(path) => match agent::config::Config::load(path) {
+                    Ok(config) => config,
+                    Err(err) => {
+                        println!("Invalid agent configuration: {}", err);
+                        std::process::exit(1);
+                    }
+                },
+                None => agent::config::Config::default(),
+            };
+
+            agent::launch(config).await;
         }
         _ => {
             println!("{}", usage);
         }
     };
 }
+
+async fn connect() -> sqlx::PgPool {
+    sqlx::postgres::PgPoolOptions::new()
+        .max_connections(16)
+        .connect(
+            &std::env::var("METRICSCAT_DATABASE_URL")
+                .unwrap_or_else(|_| "postgres:///metrics".to_string()),
+        )
+        .await
+        .unwrap()
+}
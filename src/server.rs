@@ -1,10 +1,24 @@
 use crate::agent;
+use api_key::ApiKey;
+use auth::{Session, Viewer};
+use identity::Agent;
+use payload::Payload;
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
 use sqlx::PgPool;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tags::TagFilter;
 // use chrono::prelude::*;
 
+pub mod api_key;
+pub mod auth;
+pub mod cors;
+pub mod identity;
+pub mod org;
+pub mod payload;
+pub mod tags;
+
 #[derive(Debug, PartialEq, FromFormField)]
 pub enum Interval {
     Minute1,
@@ -33,20 +47,77 @@ pub struct MetricPoint {
     recorded_at: String,
 }
 
+/// The points of one combination of the tags grouped by.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Series {
+    tags: BTreeMap<String, String>,
+    points: Vec<MetricPoint>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct LogLine {
     line: String,
+    level: Option<String>,
+    fields: HashMap<String, String>,
     recorded_at: String,
     offset: i64,
 }
 
+/// id, log_parts, separators, created_at, level, fields
+type LogRow = (
+    i64,
+    Vec<String>,
+    Vec<String>,
+    chrono::naive::NaiveDateTime,
+    Option<i16>,
+    Option<String>,
+);
+
+impl From<&LogRow> for LogLine {
+    fn from(x: &LogRow) -> LogLine {
+        let mut line = String::new();
+
+        for (idx, part) in x.1.iter().enumerate() {
+            let sep = x.2.get(idx).unwrap_or(&"".to_string()).clone();
+            line += &(part.clone() + &sep);
+        }
+
+        LogLine {
+            line,
+            level: x
+                .4
+                .and_then(agent::LogLevel::from_i16)
+                .map(|level| level.name().to_string()),
+            fields: x
+                .5
+                .as_ref()
+                .and_then(|fields| serde_json::from_str(fields).ok())
+                .unwrap_or_default(),
+            recorded_at: x.3.to_string(),
+            offset: x.0,
+        }
+    }
+}
+
 #[get("/")]
 pub fn index() -> &'static str {
     "Hello, world!"
 }
 
 #[post("/api/metrics", data = "<metrics>")]
-pub async fn api_metrics_post(metrics: Json<Vec<agent::Metric>>, pool: &State<PgPool>) {
+pub async fn api_metrics_post(
+    mut metrics: Payload<Vec<agent::Metric>>,
+    key: ApiKey<api_key::Metrics>,
+    agent: Agent,
+    pool: &State<PgPool>,
+) {
+    // Agents authenticated with a client certificate can't claim to be someone else.
+    if let Some(identity) = &agent.0 {
+        for metric in metrics.iter_mut() {
+            metric.tags.insert("agent".to_string(), identity.clone());
+        }
+    }
+
     let names: Vec<_> = metrics.iter().map(|x| x.name.clone()).collect();
 
     // Start DB transaction
@@ -92,21 +163,22 @@ pub async fn api_metrics_post(metrics: Json<Vec<agent::Metric>>, pool: &State<Pg
 
     let metrics_rows: Vec<(i64,)> = sqlx::query_as(
         "INSERT INTO metrics 
-    	(metric_name_id, value, recorded_at) 
-    	SELECT unnest($1), unnest($2), $3 
+    	(metric_name_id, value, recorded_at, organization_id) 
+    	SELECT unnest($1), unnest($2), $3, $4 
     	RETURNING id",
     )
     .bind(&ids)
     .bind(values)
     .bind(now)
+    .bind(key.organization_id)
     .fetch_all(pool.inner())
     .await
     .unwrap();
 
     let (mut tag_names, mut tag_values) = (BTreeSet::new(), BTreeSet::new());
     metrics.iter().for_each(|x| {
-        let tnames: Vec<_> = x.tags.keys().map(|x| x.clone()).collect();
-        let tvalues: Vec<_> = x.tags.values().map(|x| x.clone()).collect();
+        let tnames: Vec<_> = x.tags.keys().cloned().collect();
+        let tvalues: Vec<_> = x.tags.values().cloned().collect();
 
         tag_names.extend(tnames);
         tag_values.extend(tvalues);
@@ -114,14 +186,14 @@ pub async fn api_metrics_post(metrics: Json<Vec<agent::Metric>>, pool: &State<Pg
 
     let tag_names_rows: Vec<(i64, String)> =
         sqlx::query_as("SELECT id, name FROM tag_names WHERE name = ANY($1)")
-            .bind(&tag_names.clone().into_iter().collect::<Vec<_>>())
+            .bind(tag_names.clone().into_iter().collect::<Vec<_>>())
             .fetch_all(pool.inner())
             .await
             .unwrap();
 
     let tag_values_rows: Vec<(i64, String)> =
         sqlx::query_as("SELECT id, value FROM tag_values WHERE value = ANY($1)")
-            .bind(&tag_values.clone().into_iter().collect::<Vec<_>>())
+            .bind(tag_values.clone().into_iter().collect::<Vec<_>>())
             .fetch_all(pool.inner())
             .await
             .unwrap();
@@ -149,7 +221,7 @@ pub async fn api_metrics_post(metrics: Json<Vec<agent::Metric>>, pool: &State<Pg
             .await
             .unwrap();
 
-            tag_names_map.insert(&tag_name, row.0);
+            tag_names_map.insert(tag_name, row.0);
         }
     }
 
@@ -163,7 +235,7 @@ pub async fn api_metrics_post(metrics: Json<Vec<agent::Metric>>, pool: &State<Pg
             .await
             .unwrap();
 
-            tag_values_map.insert(&tag_value, row.0);
+            tag_values_map.insert(tag_value, row.0);
         }
     }
 
@@ -181,30 +253,63 @@ pub async fn api_metrics_post(metrics: Json<Vec<agent::Metric>>, pool: &State<Pg
     }
 
     let _rows: Vec<(i64,)> = sqlx::query_as(
-        "INSERT INTO metric_tags (metric_id, tag_name_id, tag_value_id, recorded_at)
-        SELECT unnest($1), unnest($2), unnest($3), $4
+        "INSERT INTO metric_tags (metric_id, tag_name_id, tag_value_id, recorded_at, organization_id)
+        SELECT unnest($1), unnest($2), unnest($3), $4, $5
         RETURNING id",
     )
     .bind(&metric_ids)
     .bind(&tag_name_ids)
     .bind(&tag_value_ids)
     .bind(now)
+    .bind(key.organization_id)
     .fetch_all(pool.inner())
     .await
     .unwrap();
 
     transaction.commit().await.unwrap();
+
+    if let Err(err) = org::record_usage(
+        pool.inner(),
+        key.organization_id,
+        metrics_rows.len() as i64,
+        0,
+        0,
+    )
+    .await
+    {
+        println!("Error recording usage: {}", err);
+    }
 }
 
-#[get("/api/metrics?<name>&<interval>&<range_start>&<range_end>&<function>")]
+/// One series per combination of the `group_by` tags, or a single one without `group_by`.
+#[get("/api/metrics?<name>&<interval>&<range_start>&<range_end>&<function>&<tag>&<group_by>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn api_metrics_get(
     name: &str,
     interval: Option<Interval>,
     range_start: Option<&str>,
     range_end: Option<&str>,
     function: Option<Function>,
+    tag: Vec<String>,
+    group_by: Vec<String>,
+    session: Session<Viewer>,
     pool: &State<PgPool>,
-) -> Json<Vec<MetricPoint>> {
+) -> Result<Json<Vec<Series>>, (Status, String)> {
+    // Which points to aggregate, e.g. tag=hostname:web-01.
+    let tags = tag
+        .iter()
+        .map(|tag| TagFilter::parse(tag))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| (Status::BadRequest, err))?;
+
+    // Tag keys to split the points by, e.g. group_by=hostname or group_by=dc,hostname.
+    let group_by: Vec<_> = group_by
+        .iter()
+        .flat_map(|keys| keys.split(','))
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+
     let now = chrono::offset::Utc::now().naive_utc();
     let interval = interval.unwrap_or(Interval::Minute1);
 
@@ -246,121 +351,234 @@ pub async fn api_metrics_get(
         _ => "AVG",
     };
 
-    let rows: Vec<(f64, chrono::naive::NaiveDateTime)> = sqlx::query_as(&format!(
+    let query = metrics_query(function, &tags, group_by.len());
+
+    let mut query = sqlx::query_as(&query)
+        .bind(name)
+        .bind(range_start)
+        .bind(range_end)
+        .bind(truncate_to.0)
+        .bind(session.user.organization_id);
+
+    for tag in &tags {
+        query = query.bind(&tag.key).bind(tag.pattern());
+    }
+
+    for key in &group_by {
+        query = query.bind(key);
+    }
+
+    let rows: Vec<(f64, chrono::naive::NaiveDateTime, String)> =
+        query.fetch_all(pool.inner()).await.map_err(|err| {
+            // A regex Postgres can't compile is the caller's mistake.
+            match err.as_database_error().and_then(|err| err.code()) {
+                Some(code) if code == "2201B" => (Status::BadRequest, err.to_string()),
+                _ => (Status::InternalServerError, err.to_string()),
+            }
+        })?;
+
+    Ok(Json(series(&group_by, &rows)))
+}
+
+/// The query aggregating the points of a metric into time buckets with `function`,
+/// once per combination of the values of the `group_by` tags. $1 to $5 are the name,
+/// time range, bucket size and organization, then each filter binds its key and pattern,
+/// then each key grouped by.
+fn metrics_query(function: &str, tags: &[TagFilter], group_by: usize) -> String {
+    let conditions: String = tags
+        .iter()
+        .enumerate()
+        .map(|(idx, tag)| format!("\n    \tAND {}", tag.sql(6 + idx * 2, 7 + idx * 2)))
+        .collect();
+
+    let joins: Vec<_> = (0..group_by)
+        .map(|idx| tags::group_sql(idx, 6 + tags.len() * 2 + idx))
+        .collect();
+    let values: Vec<_> = (0..group_by).map(|idx| format!("G{}.value", idx)).collect();
+
+    let (labels, group_values) = if values.is_empty() {
+        ("'[]'".to_string(), String::new())
+    } else {
+        (
+            format!("ARRAY_TO_JSON(ARRAY[{}])::TEXT", values.join(", ")),
+            format!(", {}", values.join(", ")),
+        )
+    };
+
+    format!(
         "SELECT
             {}(A.value) AS value,
-            DATE_TRUNC($4, A.recorded_at) AS recorded_at 
+            DATE_TRUNC($4, A.recorded_at) AS bucket,
+            {} AS labels
     	FROM metrics A
     	INNER JOIN metric_names B
     	ON A.metric_name_id = B.id
+    	{}
     	WHERE B.name = $1
-    	AND recorded_at > $2
-    	AND recorded_at < $3
-        GROUP BY recorded_at
-    	ORDER BY recorded_at ASC",
-        function
-    ))
-    .bind(name)
-    .bind(range_start)
-    .bind(range_end)
-    .bind(truncate_to.0)
-    .fetch_all(pool.inner())
-    .await
-    .unwrap();
+    	AND A.recorded_at > $2
+    	AND A.recorded_at < $3
+    	AND A.organization_id = $5{}
+        GROUP BY bucket{}
+    	ORDER BY bucket ASC",
+        function,
+        labels,
+        joins.join("\n    \t"),
+        conditions,
+        group_values
+    )
+}
 
-    let result: Vec<MetricPoint> = rows
-        .iter()
-        .map(|x| MetricPoint {
-            value: x.0,
-            recorded_at: x.1.to_string(),
-        })
-        .collect();
+/// Split the rows of `metrics_query` into one series per combination of labels.
+/// Points without one of the tags go in the series without it.
+fn series(
+    group_by: &[String],
+    rows: &[(f64, chrono::naive::NaiveDateTime, String)],
+) -> Vec<Series> {
+    let mut series: BTreeMap<Vec<Option<String>>, Vec<MetricPoint>> = BTreeMap::new();
+
+    for row in rows {
+        let labels: Vec<Option<String>> = serde_json::from_str(&row.2).unwrap_or_default();
+
+        series.entry(labels).or_default().push(MetricPoint {
+            value: row.0,
+            recorded_at: row.1.to_string(),
+        });
+    }
 
-    Json(result)
+    series
+        .into_iter()
+        .map(|(labels, points)| Series {
+            tags: group_by
+                .iter()
+                .zip(labels)
+                .filter_map(|(key, value)| value.map(|value| (key.clone(), value)))
+                .collect(),
+            points,
+        })
+        .collect()
 }
 
 #[post("/api/logs", data = "<log_lines>")]
-pub async fn api_logs_post(log_lines: Json<Vec<agent::LogLine>>, pool: &State<PgPool>) {
-    if log_lines.len() == 0 {
+pub async fn api_logs_post(
+    mut log_lines: Payload<Vec<agent::LogLine>>,
+    key: ApiKey<api_key::Logs>,
+    agent: Agent,
+    pool: &State<PgPool>,
+) {
+    if log_lines.is_empty() {
         return;
     }
 
-    // Grab the lines
-    let mut c = 1;
+    if let Some(identity) = &agent.0 {
+        for line in log_lines.iter_mut() {
+            line.fields.insert("agent".to_string(), identity.clone());
+        }
+    }
+
+    let log_bytes: usize = log_lines.iter().map(|x| x.line.len()).sum();
+
+    // Grab the lines; $1 is the organization
+    let mut c = 2;
     let lines: Vec<_> = log_lines
         .iter()
         .map(|x| {
             let (parts, separators) = x.tokenize();
-            let query_part = format!("(${}, ${}, TIMEZONE('UTC', NOW()))", c, c + 1,);
-
-            c += 2;
-
-            (parts, separators, query_part)
+            let level = x.level.as_ref().map(|level| level.to_i16());
+            // When the line was logged, if the agent could tell.
+            let created_at = x.created_at.as_ref().and_then(|created_at| {
+                chrono::naive::NaiveDateTime::parse_from_str(
+                    created_at,
+                    agent::parse::CREATED_AT_FORMAT,
+                )
+                .ok()
+            });
+            let fields = json!(x.fields).to_string();
+            let query_part = format!(
+                "(${}, ${}, ${}, COALESCE(${}, TIMEZONE('UTC', NOW())), TIMEZONE('UTC', NOW()), ${}::JSONB, $1)",
+                c,
+                c + 1,
+                c + 2,
+                c + 3,
+                c + 4,
+            );
+
+            c += 5;
+
+            (parts, separators, level, created_at, fields, query_part)
         })
         .collect();
 
     // Build the query from query_parts
     let v = lines
         .iter()
-        .map(|x| x.2.clone())
+        .map(|x| x.5.clone())
         .collect::<Vec<String>>()
         .join(", ");
     let q = format!(
-        "INSERT INTO logs (log_parts, separators, created_at) VALUES {} RETURNING id",
+        "INSERT INTO logs (log_parts, separators, level, created_at, recorded_at, fields, organization_id) VALUES {} RETURNING id",
         v
     );
 
-    let mut query = sqlx::query_as(&q);
+    let mut query = sqlx::query_as(&q).bind(key.organization_id);
 
     for line in lines {
         query = query
             // log_parts
             .bind(line.0)
             // separators
-            .bind(line.1);
+            .bind(line.1)
+            // level
+            .bind(line.2)
+            // created_at
+            .bind(line.3)
+            // fields
+            .bind(line.4);
     }
 
     // Execute this
-    let _rows: Vec<(i64,)> = query.fetch_all(pool.inner()).await.unwrap();
+    let rows: Vec<(i64,)> = query.fetch_all(pool.inner()).await.unwrap();
+
+    if let Err(err) = org::record_usage(
+        pool.inner(),
+        key.organization_id,
+        0,
+        rows.len() as i64,
+        log_bytes as i64,
+    )
+    .await
+    {
+        println!("Error recording usage: {}", err);
+    }
 }
 
 #[get("/api/logs?<offset>")]
-pub async fn api_logs_get(offset: Option<i64>, pool: &State<PgPool>) -> Json<Vec<LogLine>> {
+pub async fn api_logs_get(
+    offset: Option<i64>,
+    session: Session<Viewer>,
+    pool: &State<PgPool>,
+) -> Json<Vec<LogLine>> {
     let offset = offset.unwrap_or(0);
 
-    let rows: Vec<(i64, Vec<String>, Vec<String>, chrono::naive::NaiveDateTime)> = sqlx::query_as(
-        "SELECT id, log_parts, separators, created_at FROM logs WHERE id > $1 ORDER BY id DESC LIMIT 25",
+    let rows: Vec<LogRow> = sqlx::query_as(
+        "SELECT id, log_parts, separators, created_at, level, fields::TEXT FROM logs WHERE organization_id = $2 AND id > $1 ORDER BY id DESC LIMIT 25",
     )
     .bind(offset)
+    .bind(session.user.organization_id)
     .fetch_all(pool.inner())
     .await
     .unwrap();
 
-    let result: Vec<LogLine> = rows
-        .iter()
-        .map(|x| {
-            let mut line = String::new();
-
-            for (idx, part) in x.1.iter().enumerate() {
-                let sep = x.2.get(idx).unwrap_or(&"".to_string()).clone();
-                line += &(part.clone() + &sep);
-            }
-
-            LogLine {
-                line: line,
-                recorded_at: x.3.to_string(),
-                offset: x.0,
-            }
-        })
-        .collect();
+    let result: Vec<LogLine> = rows.iter().map(LogLine::from).collect();
 
     Json(result)
 }
 
-#[get("/api/logs/search?<term>&<created_at>")]
+#[get("/api/logs/search?<term>&<created_at>&<field>")]
 pub async fn api_logs_search_get(
     term: String,
     created_at: Option<String>,
+    field: Vec<String>,
+    session: Session<Viewer>,
     pool: &State<PgPool>,
 ) -> Json<Vec<LogLine>> {
     let now = chrono::offset::Utc::now().naive_utc();
@@ -376,10 +594,18 @@ pub async fn api_logs_search_get(
     // TODO: implement various tokenizers
     let term: Vec<_> = term.split_whitespace().collect();
 
-    let rows: Vec<(i64, Vec<String>, Vec<String>, chrono::naive::NaiveDateTime)> = sqlx::query_as(
-        "SELECT id, log_parts, separators, created_at
+    // Structured fields the lines must have, e.g. field=status:500.
+    let fields: HashMap<_, _> = field
+        .iter()
+        .filter_map(|field| field.split_once(':'))
+        .collect();
+
+    let rows: Vec<LogRow> = sqlx::query_as(
+        "SELECT id, log_parts, separators, created_at, level, fields::TEXT
         FROM logs
-        WHERE log_parts @> $1::VARCHAR[]
+        WHERE organization_id = $4
+        AND log_parts @> $1::VARCHAR[]
+        AND ($3::JSONB = '{}'::JSONB OR fields @> $3::JSONB)
         AND created_at < $2
         AND created_at > $2 - INTERVAL '5 minute'
         ORDER BY created_at
@@ -387,27 +613,121 @@ pub async fn api_logs_search_get(
     )
     .bind(&term)
     .bind(created_at)
+    .bind(json!(fields).to_string())
+    .bind(session.user.organization_id)
     .fetch_all(pool.inner())
     .await
     .unwrap();
 
-    let result: Vec<_> = rows
-        .iter()
-        .map(|x| {
-            let mut line = String::new();
+    let result: Vec<LogLine> = rows.iter().map(LogLine::from).collect();
 
-            for (idx, part) in x.1.iter().enumerate() {
-                let sep = x.2.get(idx).unwrap_or(&"".to_string()).clone();
-                line += &(part.clone() + &sep);
-            }
+    Json(result)
+}
 
-            LogLine {
-                line: line,
-                recorded_at: x.3.to_string(),
-                offset: x.0,
-            }
-        })
-        .collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The highest `$n` placeholder in `query`.
+    fn placeholders(query: &str) -> usize {
+        query
+            .split('$')
+            .skip(1)
+            .filter_map(|rest| {
+                let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+                digits.parse().ok()
+            })
+            .max()
+            .unwrap()
+    }
 
-    Json(result)
+    fn row(value: f64, minute: u32, labels: &str) -> (f64, chrono::naive::NaiveDateTime, String) {
+        let recorded_at = chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(12, minute, 0)
+            .unwrap();
+
+        (value, recorded_at, labels.to_string())
+    }
+
+    #[test]
+    fn aggregates_into_a_single_series_without_group_by() {
+        let query = metrics_query("AVG", &[], 0);
+
+        assert!(query.contains("'[]' AS labels"));
+        assert!(query.contains("GROUP BY bucket\n"));
+        assert!(!query.contains("LEFT JOIN"));
+        assert_eq!(placeholders(&query), 5);
+    }
+
+    #[test]
+    fn groups_by_the_truncated_timestamp_and_each_key() {
+        let tags = [TagFilter::parse("env:prod").unwrap()];
+        let query = metrics_query("MAX", &tags, 2);
+
+        assert!(query.contains("MAX(A.value) AS value"));
+        assert!(query.contains("DATE_TRUNC($4, A.recorded_at) AS bucket"));
+        assert!(query.contains("ARRAY_TO_JSON(ARRAY[G0.value, G1.value])::TEXT AS labels"));
+        assert!(query.contains("GROUP BY bucket, G0.value, G1.value"));
+        assert!(query.contains("ORDER BY bucket ASC"));
+
+        // The filter binds $6 and $7, the keys grouped by $8 and $9.
+        assert!(query.contains("N.name = $6 AND V.value LIKE $7"));
+        assert!(query.contains("N0.name = $8"));
+        assert!(query.contains("N1.name = $9"));
+        assert_eq!(placeholders(&query), 9);
+    }
+
+    #[test]
+    fn splits_rows_into_series() {
+        let group_by = ["dc".to_string(), "hostname".to_string()];
+        let rows = [
+            row(1.0, 0, r#"["eu", "web-01"]"#),
+            row(2.0, 0, r#"["eu", "web-02"]"#),
+            row(3.0, 1, r#"["eu", "web-01"]"#),
+            // No dc tag.
+            row(4.0, 1, r#"[null, "web-03"]"#),
+        ];
+
+        let series: Vec<_> = series(&group_by, &rows)
+            .into_iter()
+            .map(|series| {
+                let values: Vec<_> = series.points.iter().map(|point| point.value).collect();
+                (series.tags, values)
+            })
+            .collect();
+
+        assert_eq!(
+            series,
+            [
+                (
+                    BTreeMap::from([("hostname".to_string(), "web-03".to_string())]),
+                    vec![4.0]
+                ),
+                (
+                    BTreeMap::from([
+                        ("dc".to_string(), "eu".to_string()),
+                        ("hostname".to_string(), "web-01".to_string())
+                    ]),
+                    vec![1.0, 3.0]
+                ),
+                (
+                    BTreeMap::from([
+                        ("dc".to_string(), "eu".to_string()),
+                        ("hostname".to_string(), "web-02".to_string())
+                    ]),
+                    vec![2.0]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn returns_one_untagged_series_without_group_by() {
+        let series = series(&[], &[row(1.0, 0, "[]"), row(2.0, 1, "[]")]);
+
+        assert_eq!(series.len(), 1);
+        assert!(series[0].tags.is_empty());
+        assert_eq!(series[0].points[1].recorded_at, "2026-10-18 12:01:00");
+    }
 }
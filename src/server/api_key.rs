@@ -0,0 +1,350 @@
+// API keys agents send metrics and logs with, in the X-Api-Key header.
+//
+// Keys are random, so a plain SHA-256 is enough to store them safely; only the
+// hash and the first few characters, to tell keys apart, are kept. Each key is
+// allowed to send some kinds of data (scopes) and can be revoked at any time.
+
+use std::marker::PhantomData;
+
+use rand::Rng;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use rocket::State;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use super::auth::{Editor, Session};
+use super::org;
+
+pub const HEADER: &str = "X-Api-Key";
+
+pub const SCOPES: [&str; 3] = ["metrics", "logs", "traces"];
+
+/// What a key is allowed to send.
+pub trait Scope: Send + Sync {
+    const NAME: &'static str;
+}
+
+pub struct Metrics;
+pub struct Logs;
+
+impl Scope for Metrics {
+    const NAME: &'static str = "metrics";
+}
+
+impl Scope for Logs {
+    const NAME: &'static str = "logs";
+}
+
+/// A valid, unrevoked key with the scope `S`.
+pub struct ApiKey<S: Scope> {
+    /// Where data sent with the key goes.
+    pub organization_id: i64,
+    scope: PhantomData<S>,
+}
+
+#[rocket::async_trait]
+impl<'r, S: Scope> FromRequest<'r> for ApiKey<S> {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let key = match req.headers().get_one(HEADER) {
+            Some(key) => key,
+            None => {
+                return request::Outcome::Error((
+                    Status::Unauthorized,
+                    format!("missing {} header", HEADER),
+                ))
+            }
+        };
+
+        let pool = match req.guard::<&State<PgPool>>().await {
+            request::Outcome::Success(pool) => pool,
+            _ => {
+                return request::Outcome::Error((
+                    Status::InternalServerError,
+                    "no database".to_string(),
+                ))
+            }
+        };
+
+        let row: Option<(i64, String, Vec<String>, i64)> = match sqlx::query_as(
+            "SELECT id, name, scopes, organization_id
+            	FROM api_keys
+            	WHERE key_hash = $1 AND revoked_at IS NULL",
+        )
+        .bind(hash(key))
+        .fetch_optional(pool.inner())
+        .await
+        {
+            Ok(row) => row,
+            Err(err) => {
+                return request::Outcome::Error((Status::InternalServerError, err.to_string()))
+            }
+        };
+
+        let (id, name, scopes, organization_id) = match row {
+            Some(row) => row,
+            None => {
+                return request::Outcome::Error((
+                    Status::Unauthorized,
+                    "invalid or revoked API key".to_string(),
+                ))
+            }
+        };
+
+        if !scopes.iter().any(|scope| scope == S::NAME) {
+            return request::Outcome::Error((
+                Status::Forbidden,
+                format!("API key \"{}\" is not allowed to send {}", name, S::NAME),
+            ));
+        }
+
+        // Agents send a batch every second or so; a write each minute is plenty.
+        let _ = sqlx::query(
+            "UPDATE api_keys
+            	SET last_used_at = TIMEZONE('UTC', NOW())
+            	WHERE id = $1
+            	AND (last_used_at IS NULL OR last_used_at < TIMEZONE('UTC', NOW()) - INTERVAL '1 minute')",
+        )
+        .bind(id)
+        .execute(pool.inner())
+        .await;
+
+        request::Outcome::Success(ApiKey {
+            organization_id,
+            scope: PhantomData,
+        })
+    }
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Create a key, returning its id and the key itself, which can't be recovered later.
+pub async fn create(
+    pool: &PgPool,
+    organization_id: i64,
+    name: &str,
+    scopes: &[String],
+) -> Result<(i64, String), String> {
+    if name.trim().is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+
+    if scopes.is_empty() {
+        return Err("at least one scope is required".to_string());
+    }
+
+    for scope in scopes {
+        if !SCOPES.contains(&scope.as_str()) {
+            return Err(format!(
+                "unknown scope \"{}\", expected one of {}",
+                scope,
+                SCOPES.join(", ")
+            ));
+        }
+    }
+
+    let secret: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    let key = format!("mck_{}", secret);
+
+    let row: (i64,) = sqlx::query_as(
+        "INSERT INTO api_keys
+        	(organization_id, name, prefix, key_hash, scopes, created_at)
+        	VALUES ($1, $2, $3, $4, $5, TIMEZONE('UTC', NOW()))
+        RETURNING id",
+    )
+    .bind(organization_id)
+    .bind(name)
+    .bind(&key[..12])
+    .bind(hash(&key))
+    .bind(scopes)
+    .fetch_one(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    Ok((row.0, key))
+}
+
+pub async fn revoke(pool: &PgPool, organization_id: i64, id: i64) -> Result<(), String> {
+    let result = sqlx::query(
+        "UPDATE api_keys
+        	SET revoked_at = TIMEZONE('UTC', NOW())
+        	WHERE id = $1 AND organization_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(id)
+    .bind(organization_id)
+    .execute(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    if result.rows_affected() == 0 {
+        return Err(format!("no active API key with id {}", id));
+    }
+
+    Ok(())
+}
+
+/// A key as shown to people managing them, without the key itself.
+#[derive(serde::Serialize)]
+pub struct KeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+}
+
+type KeyRow = (
+    i64,
+    String,
+    String,
+    Vec<String>,
+    chrono::NaiveDateTime,
+    Option<chrono::NaiveDateTime>,
+    Option<chrono::NaiveDateTime>,
+);
+
+pub async fn list(pool: &PgPool, organization_id: i64) -> Result<Vec<KeyInfo>, String> {
+    let rows: Vec<KeyRow> = sqlx::query_as(
+        "SELECT id, name, prefix, scopes, created_at, last_used_at, revoked_at
+        	FROM api_keys
+        	WHERE organization_id = $1
+        	ORDER BY id",
+    )
+    .bind(organization_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| KeyInfo {
+            id: row.0,
+            name: row.1,
+            prefix: row.2,
+            scopes: row.3,
+            created_at: row.4,
+            last_used_at: row.5,
+            revoked_at: row.6,
+        })
+        .collect())
+}
+
+#[get("/api/api-keys")]
+pub async fn api_keys_get(
+    session: Session<Editor>,
+    pool: &State<PgPool>,
+) -> Result<Json<Vec<KeyInfo>>, (Status, String)> {
+    list(pool.inner(), session.user.organization_id)
+        .await
+        .map(Json)
+        .map_err(|err| (Status::InternalServerError, err))
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreatedKey {
+    pub id: i64,
+    /// Only ever shown here.
+    pub key: String,
+}
+
+#[post("/api/api-keys", data = "<key>")]
+pub async fn api_keys_post(
+    key: Json<NewKey>,
+    session: Session<Editor>,
+    pool: &State<PgPool>,
+) -> Result<Json<CreatedKey>, (Status, String)> {
+    create(
+        pool.inner(),
+        session.user.organization_id,
+        &key.name,
+        &key.scopes,
+    )
+    .await
+    .map(|(id, key)| Json(CreatedKey { id, key }))
+    .map_err(|err| (Status::UnprocessableEntity, err))
+}
+
+#[delete("/api/api-keys/<id>")]
+pub async fn api_keys_delete(
+    id: i64,
+    session: Session<Editor>,
+    pool: &State<PgPool>,
+) -> Result<(), (Status, String)> {
+    revoke(pool.inner(), session.user.organization_id, id)
+        .await
+        .map_err(|err| (Status::NotFound, err))
+}
+
+/// Manage keys from the command line: `metricscat api-key <create|list|revoke> [--org <name>]`.
+pub async fn command(pool: &PgPool, args: &[String]) -> Result<(), String> {
+    let usage =
+        "Usage: metricscat api-key <create <name> [scope,...]|list|revoke <id>> [--org <name>]";
+    let (args, org) = org::org_arg(args);
+    let organization_id = org::find(pool, &org).await?;
+
+    match args.first().map(|x| x.as_str()) {
+        Some("create") => {
+            let name = args.get(1).ok_or(usage)?;
+            let scopes: Vec<String> = match args.get(2) {
+                Some(scopes) => scopes.split(',').map(|x| x.trim().to_string()).collect(),
+                None => vec!["metrics".to_string(), "logs".to_string()],
+            };
+
+            let (id, key) = create(pool, organization_id, name, &scopes).await?;
+
+            println!("Created API key {} ({}): {}", id, scopes.join(", "), key);
+            println!("Store it now, it won't be shown again.");
+        }
+
+        Some("list") => {
+            let time = |at: &Option<chrono::NaiveDateTime>| match at {
+                Some(at) => at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                None => "-".to_string(),
+            };
+
+            println!("id\tname\tkey\tscopes\tcreated\tlast used\trevoked");
+
+            for key in list(pool, organization_id).await? {
+                println!(
+                    "{}\t{}\t{}...\t{}\t{}\t{}\t{}",
+                    key.id,
+                    key.name,
+                    key.prefix,
+                    key.scopes.join(","),
+                    key.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    time(&key.last_used_at),
+                    time(&key.revoked_at),
+                );
+            }
+        }
+
+        Some("revoke") => {
+            let id = args.get(1).and_then(|id| id.parse().ok()).ok_or(usage)?;
+
+            revoke(pool, organization_id, id).await?;
+
+            println!("Revoked API key {}", id);
+        }
+
+        _ => return Err(usage.to_string()),
+    };
+
+    Ok(())
+}
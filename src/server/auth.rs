@@ -0,0 +1,594 @@
+// User accounts and sessions for the frontend and the query API.
+//
+// Users log in with their email and password (hashed with Argon2id) and get a
+// session token, sent back either in the session cookie or as a bearer token.
+// Each user has a role, and each role can do everything the one before it can:
+//
+// - viewer: read metrics and logs,
+// - editor: also manage API keys,
+// - admin: also manage users.
+
+use std::io::BufRead;
+use std::marker::PhantomData;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use rocket::State;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use super::org;
+
+pub const COOKIE: &str = "metricscat_session";
+
+const SESSION_TTL_DAYS: i64 = 7;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Checked when logging in with an unknown email, so it takes as long as a wrong
+/// password and the response time doesn't tell which emails have an account.
+/// Same parameters as `Argon2::default()`.
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$7CbJ0czgHYyu2T2nvDc9LA$n4DdD7p3PJ9i6oMPqvfy16CHUeTC4xbO9Jc5LQLg+/c";
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct User {
+    pub id: i64,
+    pub email: String,
+    pub role: Role,
+    pub organization_id: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+type UserRow = (i64, String, String, i64, chrono::NaiveDateTime);
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> User {
+        User {
+            id: row.0,
+            email: row.1,
+            // Only valid roles are ever written.
+            role: Role::from_name(&row.2).unwrap_or(Role::Viewer),
+            organization_id: row.3,
+            created_at: row.4,
+        }
+    }
+}
+
+/// The least role a route needs.
+pub trait MinRole: Send + Sync {
+    const ROLE: Role;
+}
+
+pub struct Viewer;
+pub struct Editor;
+pub struct Admin;
+
+impl MinRole for Viewer {
+    const ROLE: Role = Role::Viewer;
+}
+
+impl MinRole for Editor {
+    const ROLE: Role = Role::Editor;
+}
+
+impl MinRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// A logged in user with at least the role `R`.
+pub struct Session<R: MinRole> {
+    pub id: i64,
+    pub user: User,
+    role: PhantomData<R>,
+}
+
+#[rocket::async_trait]
+impl<'r, R: MinRole> FromRequest<'r> for Session<R> {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let bearer = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let cookie = req
+            .cookies()
+            .get(COOKIE)
+            .map(|cookie| cookie.value().to_string());
+
+        let token = match bearer.or(cookie) {
+            Some(token) => token,
+            None => return request::Outcome::Error((Status::Unauthorized, "not logged in".into())),
+        };
+
+        let pool = match req.guard::<&State<PgPool>>().await {
+            request::Outcome::Success(pool) => pool,
+            _ => {
+                return request::Outcome::Error((
+                    Status::InternalServerError,
+                    "no database".to_string(),
+                ))
+            }
+        };
+
+        let row: Option<(i64, i64, String, String, i64, chrono::NaiveDateTime)> = match sqlx::query_as(
+            "SELECT sessions.id, users.id, users.email, users.role, users.organization_id, users.created_at
+            	FROM sessions
+            	INNER JOIN users ON users.id = sessions.user_id
+            	WHERE token_hash = $1 AND expires_at > TIMEZONE('UTC', NOW())",
+        )
+        .bind(hash(&token))
+        .fetch_optional(pool.inner())
+        .await
+        {
+            Ok(row) => row,
+            Err(err) => {
+                return request::Outcome::Error((Status::InternalServerError, err.to_string()))
+            }
+        };
+
+        let (id, user) = match row {
+            Some(row) => (row.0, User::from((row.1, row.2, row.3, row.4, row.5))),
+            None => {
+                return request::Outcome::Error((
+                    Status::Unauthorized,
+                    "invalid or expired session".to_string(),
+                ))
+            }
+        };
+
+        if user.role < R::ROLE {
+            return request::Outcome::Error((
+                Status::Forbidden,
+                format!("{} role required", R::ROLE.name()),
+            ));
+        }
+
+        request::Outcome::Success(Session {
+            id,
+            user,
+            role: PhantomData,
+        })
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Argon2 takes tens of milliseconds of CPU on purpose, so it runs on the
+/// blocking thread pool rather than holding up the async workers.
+async fn hash_password(password: &str) -> Result<String, String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    let password = password.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::encode_b64(&rand::thread_rng().gen::<[u8; 16]>())
+            .map_err(|err| err.to_string())?;
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+async fn verify_password(password: &str, password_hash: &str) -> bool {
+    let password = password.to_string();
+    let password_hash = password_hash.to_string();
+
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    })
+    .await
+    .unwrap_or(false)
+}
+
+pub async fn create_user(
+    pool: &PgPool,
+    organization_id: i64,
+    email: &str,
+    password: &str,
+    role: Role,
+) -> Result<User, String> {
+    let email = email.trim().to_lowercase();
+
+    if !email.contains('@') {
+        return Err(format!("\"{}\" is not an email address", email));
+    }
+
+    let row: Option<UserRow> = sqlx::query_as(
+        "INSERT INTO users
+        	(organization_id, email, password_hash, role, created_at)
+        	VALUES ($1, $2, $3, $4, TIMEZONE('UTC', NOW()))
+        	ON CONFLICT (email) DO NOTHING
+        RETURNING id, email, role, organization_id, created_at",
+    )
+    .bind(organization_id)
+    .bind(&email)
+    .bind(hash_password(password).await?)
+    .bind(role.name())
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    // Emails are unique across organizations, so say nothing about
+    // the taken one, which may belong to another organization's user.
+    row.map(User::from)
+        .ok_or(format!("could not create user {}", email))
+}
+
+pub async fn list_users(pool: &PgPool, organization_id: i64) -> Result<Vec<User>, String> {
+    let rows: Vec<UserRow> = sqlx::query_as(
+        "SELECT id, email, role, organization_id, created_at
+        	FROM users
+        	WHERE organization_id = $1
+        	ORDER BY id",
+    )
+    .bind(organization_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    Ok(rows.into_iter().map(User::from).collect())
+}
+
+pub async fn delete_user(pool: &PgPool, organization_id: i64, id: i64) -> Result<(), String> {
+    let result = sqlx::query("DELETE FROM users WHERE id = $1 AND organization_id = $2")
+        .bind(id)
+        .bind(organization_id)
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if result.rows_affected() == 0 {
+        return Err(format!("no user with id {}", id));
+    }
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+pub struct Credentials {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct LoggedIn {
+    pub token: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub user: User,
+}
+
+/// Log in: the session token is returned, and set in the session cookie for browsers.
+#[post("/api/sessions", data = "<credentials>")]
+pub async fn api_sessions_post(
+    credentials: Json<Credentials>,
+    cookies: &CookieJar<'_>,
+    pool: &State<PgPool>,
+) -> Result<Json<LoggedIn>, (Status, String)> {
+    let row: Option<(i64, String, String, String, i64, chrono::NaiveDateTime)> = sqlx::query_as(
+        "SELECT id, email, password_hash, role, organization_id, created_at
+        	FROM users
+        	WHERE email = $1",
+    )
+    .bind(credentials.email.trim().to_lowercase())
+    .fetch_optional(pool.inner())
+    .await
+    .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+
+    // Unknown emails are checked against a dummy hash, to take as long as known ones.
+    let password_hash = row.as_ref().map_or(DUMMY_PASSWORD_HASH, |row| &row.2);
+    let verified = verify_password(&credentials.password, password_hash).await;
+
+    let user = match row {
+        Some(row) if verified => User::from((row.0, row.1, row.3, row.4, row.5)),
+        _ => return Err((Status::Unauthorized, "wrong email or password".to_string())),
+    };
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+    let expires_at =
+        chrono::offset::Utc::now().naive_utc() + chrono::Duration::days(SESSION_TTL_DAYS);
+
+    sqlx::query(
+        "INSERT INTO sessions
+        	(user_id, token_hash, created_at, expires_at)
+        	VALUES ($1, $2, TIMEZONE('UTC', NOW()), $3)",
+    )
+    .bind(user.id)
+    .bind(hash(&token))
+    .bind(expires_at)
+    .execute(pool.inner())
+    .await
+    .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+
+    // HttpOnly so scripts can't read it; Rocket adds SameSite, and Secure with TLS.
+    cookies.add(
+        Cookie::build((COOKIE, token.clone()))
+            .http_only(true)
+            .max_age(rocket::time::Duration::days(SESSION_TTL_DAYS)),
+    );
+
+    Ok(Json(LoggedIn {
+        token,
+        expires_at,
+        user,
+    }))
+}
+
+/// Log out.
+#[delete("/api/sessions")]
+pub async fn api_sessions_delete(
+    session: Session<Viewer>,
+    cookies: &CookieJar<'_>,
+    pool: &State<PgPool>,
+) -> Result<(), (Status, String)> {
+    sqlx::query("DELETE FROM sessions WHERE id = $1")
+        .bind(session.id)
+        .execute(pool.inner())
+        .await
+        .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+
+    cookies.remove(Cookie::from(COOKIE));
+
+    Ok(())
+}
+
+#[get("/api/me")]
+pub fn api_me_get(session: Session<Viewer>) -> Json<User> {
+    Json(session.user)
+}
+
+#[get("/api/users")]
+pub async fn api_users_get(
+    session: Session<Admin>,
+    pool: &State<PgPool>,
+) -> Result<Json<Vec<User>>, (Status, String)> {
+    list_users(pool.inner(), session.user.organization_id)
+        .await
+        .map(Json)
+        .map_err(|err| (Status::InternalServerError, err))
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewUser {
+    pub email: String,
+    pub password: String,
+    pub role: Role,
+}
+
+#[post("/api/users", data = "<user>")]
+pub async fn api_users_post(
+    user: Json<NewUser>,
+    session: Session<Admin>,
+    pool: &State<PgPool>,
+) -> Result<Json<User>, (Status, String)> {
+    create_user(
+        pool.inner(),
+        session.user.organization_id,
+        &user.email,
+        &user.password,
+        user.role,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (Status::UnprocessableEntity, err))
+}
+
+#[derive(serde::Deserialize)]
+pub struct UserUpdate {
+    pub password: Option<String>,
+    pub role: Option<Role>,
+}
+
+/// Change a user's role or password. Changing the password logs them out everywhere.
+#[put("/api/users/<id>", data = "<update>")]
+pub async fn api_users_put(
+    id: i64,
+    update: Json<UserUpdate>,
+    session: Session<Admin>,
+    pool: &State<PgPool>,
+) -> Result<(), (Status, String)> {
+    // Someone has to stay admin.
+    if id == session.user.id && update.role.is_some_and(|role| role != Role::Admin) {
+        return Err((
+            Status::UnprocessableEntity,
+            "can't change your own role".to_string(),
+        ));
+    }
+
+    let password_hash = match &update.password {
+        Some(password) => Some(
+            hash_password(password)
+                .await
+                .map_err(|err| (Status::UnprocessableEntity, err))?,
+        ),
+        None => None,
+    };
+
+    let result = sqlx::query(
+        "UPDATE users
+        	SET password_hash = COALESCE($2, password_hash), role = COALESCE($3, role)
+        	WHERE id = $1 AND organization_id = $4",
+    )
+    .bind(id)
+    .bind(&password_hash)
+    .bind(update.role.map(|role| role.name()))
+    .bind(session.user.organization_id)
+    .execute(pool.inner())
+    .await
+    .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+
+    if result.rows_affected() == 0 {
+        return Err((Status::NotFound, format!("no user with id {}", id)));
+    }
+
+    if password_hash.is_some() {
+        sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+            .bind(id)
+            .execute(pool.inner())
+            .await
+            .map_err(|err| (Status::InternalServerError, err.to_string()))?;
+    }
+
+    Ok(())
+}
+
+#[delete("/api/users/<id>")]
+pub async fn api_users_delete(
+    id: i64,
+    session: Session<Admin>,
+    pool: &State<PgPool>,
+) -> Result<(), (Status, String)> {
+    if id == session.user.id {
+        return Err((
+            Status::UnprocessableEntity,
+            "can't delete yourself".to_string(),
+        ));
+    }
+
+    delete_user(pool.inner(), session.user.organization_id, id)
+        .await
+        .map_err(|err| (Status::NotFound, err))
+}
+
+/// Manage users from the command line: `metricscat user <create|list|delete> [--org <name>]`.
+/// Needed to create the first admin; passwords are read from standard input.
+pub async fn command(pool: &PgPool, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: metricscat user <create <email> <viewer|editor|admin>|list|delete <id>> [--org <name>]";
+    let (args, org) = org::org_arg(args);
+    let organization_id = org::find(pool, &org).await?;
+
+    match args.first().map(|x| x.as_str()) {
+        Some("create") => {
+            let email = args.get(1).ok_or(usage)?;
+            let role = args
+                .get(2)
+                .and_then(|role| Role::from_name(role))
+                .ok_or(usage)?;
+
+            println!("Password:");
+
+            let mut password = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut password)
+                .map_err(|err| err.to_string())?;
+
+            let user = create_user(
+                pool,
+                organization_id,
+                email,
+                password.trim_end_matches(['\r', '\n']),
+                role,
+            )
+            .await?;
+
+            println!("Created {} {} ({})", user.role.name(), user.email, user.id);
+        }
+
+        Some("list") => {
+            println!("id\temail\trole\tcreated");
+
+            for user in list_users(pool, organization_id).await? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    user.id,
+                    user.email,
+                    user.role.name(),
+                    user.created_at.format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+        }
+
+        Some("delete") => {
+            let id = args.get(1).and_then(|id| id.parse().ok()).ok_or(usage)?;
+
+            delete_user(pool, organization_id, id).await?;
+
+            println!("Deleted user {}", id);
+        }
+
+        _ => return Err(usage.to_string()),
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_real_ones() {
+        let dummy = PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap();
+        let params = argon2::Params::try_from(&dummy).unwrap();
+        let default = argon2::Params::default();
+
+        assert_eq!(dummy.algorithm, argon2::Algorithm::default().ident());
+        assert_eq!(
+            (params.m_cost(), params.t_cost(), params.p_cost()),
+            (default.m_cost(), default.t_cost(), default.p_cost())
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_passwords() {
+        let hash = hash_password("correct horse").await.unwrap();
+
+        assert!(verify_password("correct horse", &hash).await);
+        assert!(!verify_password("wrong horse", &hash).await);
+        assert!(!verify_password("correct horse", DUMMY_PASSWORD_HASH).await);
+        assert!(!verify_password("correct horse", "not a hash").await);
+        assert!(hash_password("short").await.is_err());
+    }
+}
@@ -0,0 +1,41 @@
+// Which web pages may call the API from a browser.
+//
+// Origins are listed, comma separated, in METRICSCAT_CORS_ORIGINS, e.g.
+// "https://metrics.example.com,http://localhost:3000"; the default allows the
+// frontend's development server. Listed origins can send the session cookie.
+// "*" allows any origin, but then only bearer tokens work.
+
+use rocket::http::Method;
+use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions};
+
+const DEFAULT_ORIGINS: &str = "http://localhost:3000";
+
+pub fn cors() -> Result<Cors, String> {
+    let origins =
+        std::env::var("METRICSCAT_CORS_ORIGINS").unwrap_or_else(|_| DEFAULT_ORIGINS.to_string());
+    let origins: Vec<_> = origins
+        .split(',')
+        .map(|origin| origin.trim())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+
+    // Browsers don't send cookies to wildcard origins anyway.
+    let (allowed_origins, allow_credentials) = if origins == ["*"] {
+        (AllowedOrigins::all(), false)
+    } else {
+        (AllowedOrigins::some_exact(&origins), true)
+    };
+
+    CorsOptions {
+        allowed_origins,
+        allowed_methods: [Method::Get, Method::Post, Method::Put, Method::Delete]
+            .into_iter()
+            .map(From::from)
+            .collect(),
+        allowed_headers: AllowedHeaders::some(&["Authorization", "Content-Type"]),
+        allow_credentials,
+        ..Default::default()
+    }
+    .to_cors()
+    .map_err(|err| format!("METRICSCAT_CORS_ORIGINS: {}", err))
+}
@@ -0,0 +1,48 @@
+// Which agent is sending data.
+//
+// With mutual TLS configured (ROCKET_TLS={..., mutual={ca_certs="ca.pem", mandatory=true}}),
+// agents must present a client certificate signed by the CA, and are known by its
+// subject's common name. Without it, agents are anonymous.
+
+use rocket::http::Status;
+use rocket::mtls::Certificate;
+use rocket::request::{self, FromRequest, Request};
+
+pub struct Agent(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Agent {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if !req.rocket().config().mtls_enabled() {
+            return request::Outcome::Success(Agent(None));
+        }
+
+        let certificate = match req.guard::<Certificate<'_>>().await {
+            request::Outcome::Success(certificate) => certificate,
+            _ => {
+                return request::Outcome::Error((
+                    Status::Unauthorized,
+                    "a valid client certificate is required".to_string(),
+                ))
+            }
+        };
+
+        // Certificates without a common name are known by their whole subject.
+        let subject = certificate.subject();
+        let identity = match subject.common_name() {
+            Some(name) => name.to_string(),
+            None => subject.to_string(),
+        };
+
+        if identity.is_empty() {
+            return request::Outcome::Error((
+                Status::Unauthorized,
+                "client certificate has no subject".to_string(),
+            ));
+        }
+
+        request::Outcome::Success(Agent(Some(identity)))
+    }
+}
@@ -0,0 +1,198 @@
+// Organizations: tenants sharing one server.
+//
+// Every metric, log line, API key and user belongs to an organization. Data
+// sent with an API key goes to the key's organization, and users only see their
+// own organization's data. What each organization sends is counted per day.
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::PgPool;
+
+use super::auth::{Admin, Session};
+
+/// Where data written before organizations existed went, and the default on the command line.
+pub const DEFAULT: &str = "default";
+
+#[derive(serde::Serialize)]
+pub struct Usage {
+    pub day: chrono::NaiveDate,
+    pub points: i64,
+    pub log_lines: i64,
+    pub log_bytes: i64,
+}
+
+pub async fn find(pool: &PgPool, name: &str) -> Result<i64, String> {
+    let row: Option<(i64,)> = sqlx::query_as("SELECT id FROM organizations WHERE name = $1")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    row.map(|row| row.0)
+        .ok_or(format!("no organization named \"{}\"", name))
+}
+
+pub async fn create(pool: &PgPool, name: &str) -> Result<i64, String> {
+    if name.trim().is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+
+    let row: Option<(i64,)> = sqlx::query_as(
+        "INSERT INTO organizations
+        	(name, created_at)
+        	VALUES ($1, TIMEZONE('UTC', NOW()))
+        	ON CONFLICT (name) DO NOTHING
+        RETURNING id",
+    )
+    .bind(name)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    row.map(|row| row.0)
+        .ok_or(format!("organization \"{}\" already exists", name))
+}
+
+/// Count what an organization sent today.
+pub async fn record_usage(
+    pool: &PgPool,
+    organization_id: i64,
+    points: i64,
+    log_lines: i64,
+    log_bytes: i64,
+) -> Result<(), String> {
+    sqlx::query(
+        "INSERT INTO organization_usage
+        	(organization_id, day, points, log_lines, log_bytes)
+        	VALUES ($1, TIMEZONE('UTC', NOW())::DATE, $2, $3, $4)
+        	ON CONFLICT (organization_id, day) DO UPDATE
+        	SET points = organization_usage.points + EXCLUDED.points,
+        	log_lines = organization_usage.log_lines + EXCLUDED.log_lines,
+        	log_bytes = organization_usage.log_bytes + EXCLUDED.log_bytes",
+    )
+    .bind(organization_id)
+    .bind(points)
+    .bind(log_lines)
+    .bind(log_bytes)
+    .execute(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+/// Usage over the last `days` days, oldest first.
+pub async fn usage(pool: &PgPool, organization_id: i64, days: i64) -> Result<Vec<Usage>, String> {
+    let rows: Vec<(chrono::NaiveDate, i64, i64, i64)> = sqlx::query_as(
+        "SELECT day, points, log_lines, log_bytes
+        	FROM organization_usage
+        	WHERE organization_id = $1
+        	AND day > TIMEZONE('UTC', NOW())::DATE - $2::INTEGER
+        	ORDER BY day",
+    )
+    .bind(organization_id)
+    .bind(days as i32)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| Usage {
+            day: row.0,
+            points: row.1,
+            log_lines: row.2,
+            log_bytes: row.3,
+        })
+        .collect())
+}
+
+#[get("/api/usage?<days>")]
+pub async fn api_usage_get(
+    days: Option<i64>,
+    session: Session<Admin>,
+    pool: &State<PgPool>,
+) -> Result<Json<Vec<Usage>>, (Status, String)> {
+    usage(
+        pool.inner(),
+        session.user.organization_id,
+        days.unwrap_or(30).clamp(1, 366),
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (Status::InternalServerError, err))
+}
+
+/// Take `--org <name>` out of command line arguments; the default organization without it.
+pub fn org_arg(args: &[String]) -> (Vec<String>, String) {
+    let mut rest = Vec::new();
+    let mut org = DEFAULT.to_string();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.as_slice().first()) {
+            ("--org", Some(name)) => {
+                org = name.clone();
+                args.next();
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+
+    (rest, org)
+}
+
+/// Manage organizations from the command line: `metricscat org <create|list|usage>`.
+pub async fn command(pool: &PgPool, args: &[String]) -> Result<(), String> {
+    let usage_text = "Usage: metricscat org <create <name>|list|usage <name> [days]>";
+
+    match args.first().map(|x| x.as_str()) {
+        Some("create") => {
+            let name = args.get(1).ok_or(usage_text)?;
+            let id = create(pool, name).await?;
+
+            println!("Created organization {} ({})", name, id);
+        }
+
+        Some("list") => {
+            let rows: Vec<(i64, String, chrono::NaiveDateTime)> =
+                sqlx::query_as("SELECT id, name, created_at FROM organizations ORDER BY id")
+                    .fetch_all(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+            println!("id\tname\tcreated");
+
+            for row in &rows {
+                println!(
+                    "{}\t{}\t{}",
+                    row.0,
+                    row.1,
+                    row.2.format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+        }
+
+        Some("usage") => {
+            let name = args.get(1).ok_or(usage_text)?;
+            let days = match args.get(2) {
+                Some(days) => days.parse().map_err(|_| usage_text)?,
+                None => 30,
+            };
+
+            println!("day\tpoints\tlog lines\tlog bytes");
+
+            for day in usage(pool, find(pool, name).await?, days).await? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    day.day, day.points, day.log_lines, day.log_bytes
+                );
+            }
+        }
+
+        _ => return Err(usage_text.to_string()),
+    };
+
+    Ok(())
+}
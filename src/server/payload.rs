@@ -0,0 +1,181 @@
+// Request bodies sent by agents: JSON, optionally compressed with gzip or zstd
+// as announced by the Content-Encoding header.
+
+use std::io::Read;
+
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::Status;
+use rocket::Request;
+
+/// Largest body accepted, after decompression. Configurable with the `payload` limit,
+/// e.g. ROCKET_LIMITS={payload="32MiB"}.
+const DEFAULT_LIMIT_MIB: u64 = 16;
+
+pub struct Payload<T>(pub T);
+
+impl<T> std::ops::Deref for Payload<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for Payload<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: serde::de::DeserializeOwned> FromData<'r> for Payload<T> {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req
+            .limits()
+            .get("payload")
+            .unwrap_or_else(|| DEFAULT_LIMIT_MIB.mebibytes());
+
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return data::Outcome::Error((
+                    Status::PayloadTooLarge,
+                    format!("body is larger than {}", limit),
+                ))
+            }
+            Err(err) => return data::Outcome::Error((Status::BadRequest, err.to_string())),
+        };
+
+        let encoding = req
+            .headers()
+            .get_one("Content-Encoding")
+            .unwrap_or("identity")
+            .to_string();
+
+        // Inflating a large body takes a while, keep it off the async runtime.
+        let limit = limit.as_u64();
+        let body = match tokio::task::spawn_blocking(move || decode(&encoding, body, limit)).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(err)) => return data::Outcome::Error(err),
+            Err(err) => {
+                return data::Outcome::Error((Status::InternalServerError, err.to_string()))
+            }
+        };
+
+        match serde_json::from_slice(&body) {
+            Ok(payload) => data::Outcome::Success(Payload(payload)),
+            Err(err) => data::Outcome::Error((Status::UnprocessableEntity, err.to_string())),
+        }
+    }
+}
+
+/// Undo the Content-Encoding of `body`, refusing to inflate past `limit` bytes.
+fn decode(encoding: &str, body: Vec<u8>, limit: u64) -> Result<Vec<u8>, (Status, String)> {
+    match encoding {
+        "identity" => Ok(body),
+        "gzip" => decompress(flate2::read::GzDecoder::new(&body[..]), limit)
+            .map_err(|err| (Status::BadRequest, err)),
+        "zstd" => {
+            let decoder = zstd::stream::read::Decoder::new(&body[..])
+                .map_err(|err| (Status::BadRequest, err.to_string()))?;
+
+            decompress(decoder, limit).map_err(|err| (Status::BadRequest, err))
+        }
+        encoding => Err((
+            Status::UnsupportedMediaType,
+            format!("unsupported Content-Encoding: {}", encoding),
+        )),
+    }
+}
+
+/// Read everything from `decoder`, refusing to inflate past `limit` bytes.
+fn decompress(decoder: impl Read, limit: u64) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+
+    decoder
+        .take(limit + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| err.to_string())?;
+
+    if body.len() as u64 > limit {
+        return Err(format!(
+            "body is larger than {} bytes once decompressed",
+            limit
+        ));
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(body: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(body, 0).unwrap()
+    }
+
+    #[test]
+    fn decodes_identity() {
+        assert_eq!(decode("identity", b"[]".to_vec(), 16), Ok(b"[]".to_vec()));
+    }
+
+    #[test]
+    fn decodes_gzip() {
+        assert_eq!(decode("gzip", gzip(b"[1, 2]"), 16), Ok(b"[1, 2]".to_vec()));
+    }
+
+    #[test]
+    fn decodes_zstd() {
+        assert_eq!(decode("zstd", zstd(b"[1, 2]"), 16), Ok(b"[1, 2]".to_vec()));
+    }
+
+    #[test]
+    fn rejects_unknown_encodings() {
+        let (status, _) = decode("br", b"[]".to_vec(), 16).unwrap_err();
+
+        assert_eq!(status, Status::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn rejects_corrupt_bodies() {
+        assert_eq!(
+            decode("gzip", b"[]".to_vec(), 16).unwrap_err().0,
+            Status::BadRequest
+        );
+        assert_eq!(
+            decode("zstd", b"[]".to_vec(), 16).unwrap_err().0,
+            Status::BadRequest
+        );
+    }
+
+    #[test]
+    fn refuses_to_inflate_past_the_limit() {
+        // A few KiB that inflate to a MiB of zeros.
+        let bomb = vec![0; 1 << 20];
+        let limit = 64 * 1024;
+
+        for (encoding, body) in [("gzip", gzip(&bomb)), ("zstd", zstd(&bomb))] {
+            assert!(body.len() < limit);
+
+            let (status, err) = decode(encoding, body, limit as u64).unwrap_err();
+            assert_eq!(status, Status::BadRequest);
+            assert_eq!(err, "body is larger than 65536 bytes once decompressed");
+        }
+
+        // Right at the limit is fine.
+        assert_eq!(
+            decode("gzip", gzip(&bomb[..limit]), limit as u64).map(|body| body.len()),
+            Ok(limit)
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// Tag filters and groups for the metrics query API, e.g. `tag=hostname:web-01`.
+//
+// - `key:value` keeps points tagged `key` with that value,
+// - `key!=value` drops them,
+// - `key=~regex` keeps points whose `key` tag matches the (Postgres) regex,
+// - `key!~regex` drops them.
+//
+// Regexes are checked up front, so only the syntax Postgres shares with
+// the regex crate can be used: no backreferences or lookarounds.
+//
+// `*` in the value of `:` and `!=` matches anything, e.g. `hostname:web-*`.
+// Negative filters also keep points without the tag at all.
+
+#[derive(Debug, PartialEq)]
+pub enum Matcher {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+#[derive(Debug)]
+pub struct TagFilter {
+    pub key: String,
+    pub matcher: Matcher,
+    pub value: String,
+}
+
+impl TagFilter {
+    pub fn parse(filter: &str) -> Result<TagFilter, String> {
+        let invalid = || {
+            format!(
+                "invalid tag filter \"{}\", expected key:value, key!=value, key=~regex or key!~regex",
+                filter
+            )
+        };
+
+        let at = filter.find([':', '!', '=', '~']).ok_or_else(invalid)?;
+        let (key, rest) = filter.split_at(at);
+
+        let (matcher, value) = if let Some(value) = rest.strip_prefix("!=") {
+            (Matcher::NotEqual, value)
+        } else if let Some(value) = rest.strip_prefix("!~") {
+            (Matcher::NotRegex, value)
+        } else if let Some(value) = rest.strip_prefix("=~") {
+            (Matcher::Regex, value)
+        } else if let Some(value) = rest.strip_prefix(':') {
+            (Matcher::Equal, value)
+        } else {
+            return Err(invalid());
+        };
+
+        if key.is_empty() {
+            return Err(invalid());
+        }
+
+        if matches!(matcher, Matcher::Regex | Matcher::NotRegex) {
+            regex::Regex::new(value)
+                .map_err(|err| format!("invalid regex in tag filter \"{}\": {}", filter, err))?;
+        }
+
+        Ok(TagFilter {
+            key: key.to_string(),
+            matcher,
+            value: value.to_string(),
+        })
+    }
+
+    /// What the tag value is compared to: a LIKE pattern, or the regex as is.
+    pub fn pattern(&self) -> String {
+        match self.matcher {
+            Matcher::Equal | Matcher::NotEqual => self
+                .value
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+                .replace('*', "%"),
+            Matcher::Regex | Matcher::NotRegex => self.value.clone(),
+        }
+    }
+
+    /// A condition on the point `A.id`, with the key bound to `$key` and `pattern()` to `$pattern`.
+    pub fn sql(&self, key: usize, pattern: usize) -> String {
+        let (not, operator) = match self.matcher {
+            Matcher::Equal => ("", "LIKE"),
+            Matcher::NotEqual => ("NOT ", "LIKE"),
+            Matcher::Regex => ("", "~"),
+            Matcher::NotRegex => ("NOT ", "~"),
+        };
+
+        format!(
+            "{}EXISTS (
+            	SELECT 1 FROM metric_tags T
+            	INNER JOIN tag_names N ON N.id = T.tag_name_id
+            	INNER JOIN tag_values V ON V.id = T.tag_value_id
+            	WHERE T.metric_id = A.id AND N.name = ${} AND V.value {} ${}
+            )",
+            not, key, operator, pattern
+        )
+    }
+}
+
+/// Joins the value of the tag named `$key` to the point `A.id` as `G<idx>.value`, NULL without it.
+pub fn group_sql(idx: usize, key: usize) -> String {
+    format!(
+        "LEFT JOIN (metric_tags T{idx}
+        	INNER JOIN tag_names N{idx} ON N{idx}.id = T{idx}.tag_name_id AND N{idx}.name = ${key}
+        	INNER JOIN tag_values G{idx} ON G{idx}.id = T{idx}.tag_value_id)
+        ON T{idx}.metric_id = A.id",
+        idx = idx,
+        key = key
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(filter: &str) -> (String, Matcher, String) {
+        let filter = TagFilter::parse(filter).unwrap();
+        (filter.key, filter.matcher, filter.value)
+    }
+
+    #[test]
+    fn parses_each_matcher() {
+        assert_eq!(
+            parse("hostname:web-01"),
+            ("hostname".to_string(), Matcher::Equal, "web-01".to_string())
+        );
+        assert_eq!(
+            parse("hostname!=web-01"),
+            (
+                "hostname".to_string(),
+                Matcher::NotEqual,
+                "web-01".to_string()
+            )
+        );
+        assert_eq!(
+            parse("hostname=~^web-0[12]$"),
+            (
+                "hostname".to_string(),
+                Matcher::Regex,
+                "^web-0[12]$".to_string()
+            )
+        );
+        assert_eq!(
+            parse("hostname!~^db-"),
+            (
+                "hostname".to_string(),
+                Matcher::NotRegex,
+                "^db-".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_separators_in_values() {
+        assert_eq!(
+            parse("url:http://example.com:8080/"),
+            (
+                "url".to_string(),
+                Matcher::Equal,
+                "http://example.com:8080/".to_string()
+            )
+        );
+        assert_eq!(
+            parse("query!=a=b"),
+            ("query".to_string(), Matcher::NotEqual, "a=b".to_string())
+        );
+        assert_eq!(
+            parse("query=~a=b|c:d"),
+            ("query".to_string(), Matcher::Regex, "a=b|c:d".to_string())
+        );
+        assert_eq!(
+            parse("env:"),
+            ("env".to_string(), Matcher::Equal, "".to_string())
+        );
+    }
+
+    #[test]
+    fn turns_wildcards_into_like_patterns() {
+        assert_eq!(
+            TagFilter::parse("hostname:web-*").unwrap().pattern(),
+            "web-%"
+        );
+        assert_eq!(
+            TagFilter::parse("path!=*/tmp/*").unwrap().pattern(),
+            "%/tmp/%"
+        );
+        // Only `*` is a wildcard.
+        assert_eq!(
+            TagFilter::parse("file:100%_done\\").unwrap().pattern(),
+            "100\\%\\_done\\\\"
+        );
+        // Regexes are passed as they are.
+        assert_eq!(
+            TagFilter::parse("hostname=~web-.*").unwrap().pattern(),
+            "web-.*"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert!(TagFilter::parse("hostname=~web-(").is_err());
+        assert!(TagFilter::parse("hostname!~[a-").is_err());
+        // Not a regex, so nothing to check.
+        assert!(TagFilter::parse("hostname:web-(").is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for filter in [
+            "hostname",
+            ":web-01",
+            "=~web",
+            "hostname=web-01",
+            "hostname~web",
+            "hostname!web",
+        ] {
+            assert!(
+                TagFilter::parse(filter).is_err(),
+                "{} should be rejected",
+                filter
+            );
+        }
+    }
+}